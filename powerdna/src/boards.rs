@@ -1,13 +1,153 @@
 pub(crate) mod ai201;
+pub(crate) mod analog_input;
 pub(crate) mod dio405;
+pub(crate) mod simulated;
 
+use crate::boards::analog_input::AnalogInput;
+use crate::config::{BoardConfig, BoardModel, ChannelConfig};
+use crate::daq::Daq;
+use crate::inventory;
+use crate::queue::BoundedSender;
+use crate::results::PowerDnaError;
+use crate::stats::BoardStats;
+use crate::{DaqError, Gain};
+use core::mem;
 use core::option::Option::None;
-use powerdna_sys::{pDQBCB, DQACBCFG};
+use core::sync::atomic::AtomicBool;
+use powerdna_sys::{
+    pDATACONV, pDQBCB, DqConvRaw2ScalePdc, DQACBCFG, DQ_ACBMODE_CYCLE, DQ_ACB_DATA_RAW,
+    DQ_ACB_DATA_TSCOPY, DQ_ACB_DIRECTION_INPUT,
+};
+use std::os::raw::c_char;
+use std::sync::Arc;
 
 pub(crate) const EVENT_TIMEOUT: i32 = 1000;
 
-pub trait Bcb {
-    fn bcb(&self) -> pDQBCB;
+/// What a stream config needs to know about an analogue input layer.
+#[derive(Debug)]
+pub(crate) struct ModelSpec {
+    /// Model number reported by the IOM, e.g. `0x201`.
+    pub model_id: u16,
+    pub channels: u32,
+    /// Highest sampling rate in Hz.
+    pub max_freq: u32,
+    /// Gain factors as written in the stream config, paired with the layer's gain code.
+    pub gains: &'static [(u32, u32)],
+}
+
+impl ModelSpec {
+    pub(crate) fn name(&self) -> String {
+        inventory::model_name(self.model_id)
+    }
+
+    pub(crate) fn gain_code(&self, gain: Gain) -> Option<u32> {
+        self.gains
+            .iter()
+            .find(|(factor, _)| *factor == gain.factor())
+            .map(|(_, code)| *code)
+    }
+}
+
+/// An analogue input layer that `AnalogInput` can drive. Supporting another layer means
+/// implementing this and adding a variant to `BoardModel`; the defaults suit layers that share the
+/// AI-201's 16-bit raw readings and channel list encoding.
+pub(crate) trait AnalogInputModel: Send + Sync + 'static {
+    const SPEC: ModelSpec;
+
+    /// Layer configuration flags passed to `DqAcbInitOps`.
+    const LAYER_CONFIG: u32;
+
+    /// Channel list entry for a configured channel.
+    fn encode_channel(channel: &ChannelConfig) -> Result<u32, DaqError> {
+        let gain = Self::SPEC
+            .gain_code(channel.gain)
+            .ok_or(DaqError::GainConfigError)?;
+        Ok(channel.id as u32 | (gain << 8))
+    }
+
+    /// Sets up the ACB for scans of `scan_size` readings, the last two of which hold the timestamp.
+    fn configure_acb(acb_cfg: &mut DQACBCFG, scan_size: u32, frame_size: u32) {
+        acb_cfg.samplesz = mem::size_of::<u16>() as u32; // size of single reading
+        acb_cfg.scansz = scan_size;
+        acb_cfg.framesize = frame_size;
+        acb_cfg.frames = 12; // # of frames in circular buffer
+        acb_cfg.mode = DQ_ACBMODE_CYCLE;
+        acb_cfg.dirflags = DQ_ACB_DIRECTION_INPUT | DQ_ACB_DATA_RAW | DQ_ACB_DATA_TSCOPY;
+    }
+
+    /// Converts `scans` scans of raw readings into volts.
+    fn convert(
+        pdc: pDATACONV,
+        channels: &[u32],
+        scans: u32,
+        raw: *mut c_char,
+        scaled: &mut [f64],
+    ) -> Result<(), PowerDnaError> {
+        let chans = channels.len() as u32;
+        parse_err!(DqConvRaw2ScalePdc(
+            pdc,
+            channels.as_ptr(),
+            chans,
+            scans * chans,
+            raw,
+            scaled.as_mut_ptr()
+        ))?;
+        Ok(())
+    }
+}
+
+/// A frame as read from an input board: its scaled samples, with two unused slots per scan where
+/// the timestamp was, and the raw timestamp of each scan.
+pub(crate) type RawFrame = (Vec<f64>, Vec<u32>);
+
+/// Configures the input layer named by the board's `type`.
+pub(crate) fn open_input(
+    daq: Arc<Daq>,
+    freq: u32,
+    frame_size: u32,
+    board_config: &BoardConfig,
+    stats: Arc<BoardStats>,
+    out: BoundedSender<RawFrame>,
+) -> Result<Arc<dyn InputBoard>, DaqError> {
+    match board_config.model {
+        BoardModel::Ai201 => Ok(Arc::new(AnalogInput::<ai201::Ai201>::new(
+            daq,
+            freq,
+            frame_size,
+            board_config,
+            stats,
+            out,
+        )?)),
+    }
+}
+
+/// A board that streams frames of analogue samples to the muxer thread.
+pub trait InputBoard: Send + Sync {
+    fn sample(&self, stop: Arc<AtomicBool>);
+
+    /// Sampling rate accepted by the board.
+    fn actual_freq(&self) -> f32;
+
+    /// Hardware block control buffer, if the board is backed by a real layer and must be enabled
+    /// with `DqeEnable`.
+    fn bcb(&self) -> Option<pDQBCB>;
+}
+
+/// Applies each channel's calibration in place to a frame laid out as scans of one value per
+/// channel followed by two timestamp slots.
+pub(crate) fn calibrate(data: &mut [f64], channels: &[ChannelConfig]) {
+    for scan in data.chunks_mut(channels.len() + 2) {
+        for (value, channel) in scan.iter_mut().zip(channels) {
+            *value = channel.calibrate(*value);
+        }
+    }
+}
+
+/// A digital board that drives the buzzer and reports rising edges on line 0.
+pub trait OutputBoard: Send + Sync {
+    fn sample(&self, stop: Arc<AtomicBool>);
+
+    fn write(&self, value: u32) -> Result<(), DaqError>;
 }
 
 pub(crate) trait Empty {
@@ -35,3 +175,96 @@ impl Empty for DQACBCFG {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boards::ai201::Ai201;
+    use powerdna_sys::{
+        DQ_AI201_GAIN_10_100, DQ_AI201_GAIN_1_100, DQ_AI201_GAIN_2_100, DQ_AI201_GAIN_5_100,
+    };
+    use serde_json::json;
+
+    /// An eight channel layer with its own gain table, to check that nothing assumes the AI-201's.
+    struct Ai208;
+
+    impl AnalogInputModel for Ai208 {
+        const SPEC: ModelSpec = ModelSpec {
+            model_id: 0x208,
+            channels: 8,
+            max_freq: 1000,
+            gains: &[(1, 0), (10, 1), (100, 2), (1000, 3)],
+        };
+
+        const LAYER_CONFIG: u32 = 0;
+    }
+
+    fn channel(id: u8, gain: u32) -> ChannelConfig {
+        serde_json::from_value(json!({ "id": id, "gain": gain })).unwrap()
+    }
+
+    #[test]
+    fn looks_up_each_models_gain_codes() {
+        let models = [(
+            BoardModel::Ai201,
+            [
+                (1, DQ_AI201_GAIN_1_100),
+                (2, DQ_AI201_GAIN_2_100),
+                (5, DQ_AI201_GAIN_5_100),
+                (10, DQ_AI201_GAIN_10_100),
+            ],
+        )];
+        for (model, gains) in models {
+            let spec = model.spec();
+            assert_eq!(BoardModel::from_model_id(spec.model_id), Some(model));
+            for (factor, code) in gains {
+                assert_eq!(spec.gain_code(Gain(factor)), Some(code), "{:?}", model);
+            }
+            assert_eq!(spec.gain_code(Gain(3)), None, "{:?}", model);
+        }
+        assert_eq!(BoardModel::Ai201.spec().name(), "AI-201");
+    }
+
+    #[test]
+    fn encodes_channels_with_the_models_gain_codes() {
+        assert_eq!(
+            Ai201::encode_channel(&channel(7, 5)).unwrap(),
+            7 | (DQ_AI201_GAIN_5_100 << 8)
+        );
+        assert!(matches!(
+            Ai201::encode_channel(&channel(7, 100)),
+            Err(DaqError::GainConfigError)
+        ));
+
+        assert_eq!(
+            Ai208::encode_channel(&channel(3, 100)).unwrap(),
+            3 | (2 << 8)
+        );
+        assert!(matches!(
+            Ai208::encode_channel(&channel(3, 5)),
+            Err(DaqError::GainConfigError)
+        ));
+        assert_eq!(Ai208::SPEC.name(), "AI-208");
+    }
+
+    #[test]
+    fn configures_the_acb_for_timestamped_scans() {
+        let mut acb_cfg = DQACBCFG::empty();
+        Ai208::configure_acb(&mut acb_cfg, 6, 100);
+        assert_eq!(acb_cfg.samplesz, 2);
+        assert_eq!(acb_cfg.scansz, 6);
+        assert_eq!(acb_cfg.framesize, 100);
+        assert_eq!(acb_cfg.mode, DQ_ACBMODE_CYCLE);
+        assert_ne!(acb_cfg.dirflags & DQ_ACB_DATA_TSCOPY, 0);
+    }
+
+    #[test]
+    fn calibrates_each_channel_but_not_the_timestamps() {
+        let mut channels = vec![channel(0, 1), channel(1, 1)];
+        channels[1].scale = 2.0;
+        channels[1].offset = 1.0;
+        let mut data = vec![1.0, 1.0, 9.0, 9.0, 2.0, 2.0, 9.0, 9.0];
+        calibrate(&mut data, &channels);
+        assert_eq!(data, [1.0, 3.0, 9.0, 9.0, 2.0, 5.0, 9.0, 9.0]);
+    }
+}
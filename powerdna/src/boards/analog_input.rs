@@ -0,0 +1,261 @@
+use crate::boards::{calibrate, AnalogInputModel, Empty, InputBoard, RawFrame, EVENT_TIMEOUT};
+use crate::config::{BoardConfig, ChannelConfig};
+use crate::daq::Daq;
+use crate::engine::InterfaceType;
+use crate::queue::BoundedSender;
+use crate::results::PowerDnaError;
+use crate::stats::BoardStats;
+use crate::DaqError;
+use core::marker::{PhantomData, Send, Sync};
+use core::result::Result;
+use core::result::Result::{Err, Ok};
+use core::sync::atomic::{AtomicBool, Ordering};
+use powerdna_sys::{
+    pDATACONV, pDQBCB, DQ_eBufferDone, DQ_eBufferError, DQ_eFrameDone, DQ_ePacketLost,
+    DQ_ePacketOOB, DqAcbGetScansCopy, DqAcbInitOps, DqeSetEvent, DqeWaitForEvent, DQACBCFG,
+    DQ_LNCL_TIMESTAMP,
+};
+use std::os::raw::c_char;
+use std::ptr;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+/// An analogue input layer streaming through an ACB. Everything specific to the layer comes from
+/// `M`.
+pub struct AnalogInput<M: AnalogInputModel> {
+    device: u8,
+    bcb: pDQBCB,
+    channels: Vec<u32>,
+    channel_configs: Vec<ChannelConfig>,
+    pdc: pDATACONV,
+    acb_cfg: DQACBCFG,
+    daq: Arc<Daq>,
+    buffer_size: usize,
+    actual_freq: f32,
+    stats: Arc<BoardStats>,
+    out: BoundedSender<RawFrame>,
+    model: PhantomData<M>,
+}
+
+impl<M: AnalogInputModel> AnalogInput<M> {
+    pub fn new(
+        daq: Arc<Daq>,
+        freq: u32,
+        frame_size: u32,
+        board_config: &BoardConfig,
+        stats: Arc<BoardStats>,
+        out: BoundedSender<RawFrame>,
+    ) -> Result<Self, DaqError> {
+        let BoardConfig {
+            device, channels, ..
+        } = board_config;
+        daq.enter_config_mode(*device)?;
+        let bcb = daq.create_acb(*device, InterfaceType::Input)?;
+
+        let mut channel_list = channels
+            .iter()
+            .map(M::encode_channel)
+            .collect::<Result<Vec<u32>, DaqError>>()?;
+        channel_list.push(0);
+        channel_list.push(DQ_LNCL_TIMESTAMP);
+
+        let mut acb_cfg = DQACBCFG::empty();
+        M::configure_acb(&mut acb_cfg, channel_list.len() as u32, frame_size);
+
+        let mut card_cfg = M::LAYER_CONFIG;
+        let mut actual_freq = freq as f32;
+        let mut num_channels = channel_list.len() as u32;
+
+        // mutation
+        parse_err!(DqAcbInitOps(
+            bcb,
+            &mut card_cfg,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut actual_freq,
+            ptr::null_mut(),
+            &mut num_channels,
+            channel_list.as_mut_ptr(),
+            ptr::null_mut(),
+            &mut acb_cfg
+        ))?;
+        parse_err!(DqeSetEvent(
+            bcb,
+            DQ_eFrameDone | DQ_ePacketLost | DQ_eBufferError | DQ_ePacketOOB | DQ_eBufferDone
+        ))?;
+
+        let pdc = daq.get_data_converter(*device, &channel_list)?;
+
+        let buffer_size = (acb_cfg.framesize * acb_cfg.scansz) as usize;
+
+        Ok(AnalogInput {
+            device: *device,
+            bcb,
+            channels: channel_list,
+            channel_configs: channels.clone(),
+            pdc,
+            acb_cfg,
+            daq,
+            buffer_size,
+            actual_freq,
+            stats,
+            out,
+            model: PhantomData,
+        })
+    }
+
+    fn wait_for_event(&self) -> Result<u32, PowerDnaError> {
+        let mut events: u32 = 0;
+        parse_err!(DqeWaitForEvent(&self.bcb, 1, 0, EVENT_TIMEOUT, &mut events))?;
+        Ok(events)
+    }
+
+    fn extract_timestamps(&self, raw_buffer: &[u16], scans: usize) -> Result<Vec<u32>, DaqError> {
+        let mut timestamps: Vec<u32> = Vec::with_capacity(scans);
+        let num_chans = self.channels.len();
+
+        for scan in 1..(scans + 1) {
+            let upper_half = *(raw_buffer
+                .get((scan * num_chans) - 2)
+                .ok_or(DaqError::TimestampDecodeError)?) as u32;
+            let lower_half = *(raw_buffer
+                .get((scan * num_chans) - 1)
+                .ok_or(DaqError::TimestampDecodeError)?) as u32;
+            let timestamp: u32 = (upper_half << 16) | lower_half;
+            timestamps.push(timestamp);
+        }
+
+        Ok(timestamps)
+    }
+
+    fn get_scaled_data(&self, raw_buffer: &mut Vec<u16>) -> Result<Vec<RawFrame>, PowerDnaError> {
+        let framesize: u32 = self.acb_cfg.framesize;
+        let mut received_scans: u32 = 0;
+        let mut remaining_scans: u32 = 0;
+
+        let buffer_ptr = raw_buffer.as_mut_ptr() as *mut c_char;
+
+        let mut scaled_frames: Vec<RawFrame> = vec![];
+
+        let mut data_available = true;
+
+        while data_available {
+            parse_err!(DqAcbGetScansCopy(
+                self.bcb,
+                buffer_ptr,
+                framesize,
+                framesize,
+                &mut received_scans,
+                &mut remaining_scans
+            ))?;
+
+            let mut scaled_buffer: Vec<f64> = vec![0.0; self.buffer_size];
+
+            let timestamps = match self.extract_timestamps(raw_buffer, received_scans as usize) {
+                Ok(val) => val,
+                Err(_) => {
+                    self.stats.timestamp_errors.fetch_add(1, Ordering::Relaxed);
+                    warn!("Failed to parse timestamp data, skipping frame");
+                    continue;
+                }
+            };
+
+            M::convert(
+                self.pdc,
+                &self.channels,
+                received_scans,
+                buffer_ptr,
+                &mut scaled_buffer,
+            )?;
+
+            calibrate(&mut scaled_buffer, &self.channel_configs);
+
+            scaled_frames.push((scaled_buffer, timestamps));
+
+            data_available = remaining_scans > framesize;
+        }
+
+        Ok(scaled_frames)
+    }
+}
+
+impl<M: AnalogInputModel> InputBoard for AnalogInput<M> {
+    fn sample(&self, stop: Arc<AtomicBool>) {
+        let mut raw_buffer = vec![0; self.buffer_size];
+        'outer: loop {
+            let events = match self.wait_for_event() {
+                Err(PowerDnaError::TimeoutError) => {
+                    match stop.load(Ordering::SeqCst) {
+                        true => break,
+                        false => continue,
+                    };
+                }
+                Err(err) => {
+                    error!(error = ?err, "DqeWaitForEvent failed");
+                    break;
+                }
+                Ok(val) => val,
+            };
+
+            if events & DQ_ePacketLost != 0 {
+                self.stats.packets_lost.fetch_add(1, Ordering::Relaxed);
+                warn!("DQ_ePacketLost");
+            }
+            if events & DQ_eBufferError != 0 {
+                self.stats.buffer_errors.fetch_add(1, Ordering::Relaxed);
+                warn!("DQ_eBufferError");
+            }
+            if events & DQ_ePacketOOB != 0 {
+                self.stats.packets_oob.fetch_add(1, Ordering::Relaxed);
+                warn!("DQ_ePacketOOB");
+            }
+
+            if events & DQ_eFrameDone == 0 {
+                continue;
+            }
+
+            let scaled_data = match self.get_scaled_data(&mut raw_buffer) {
+                Ok(val) => val,
+                Err(err) => {
+                    warn!(error = %err, "Failed to get scaled data, skipping frame");
+                    continue;
+                }
+            };
+
+            for frame in scaled_data {
+                match self.out.send(frame) {
+                    Ok(_) => {
+                        self.stats.frames.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        // the muxer thread exits by itself at the end of a timed acquisition
+                        if !stop.load(Ordering::SeqCst) {
+                            error!(error = %err, "Failed to send frame data to muxer thread");
+                        }
+                        break 'outer;
+                    }
+                };
+            }
+        }
+    }
+
+    fn actual_freq(&self) -> f32 {
+        self.actual_freq
+    }
+
+    fn bcb(&self) -> Option<pDQBCB> {
+        Some(self.bcb)
+    }
+}
+
+unsafe impl<M: AnalogInputModel> Send for AnalogInput<M> {}
+
+unsafe impl<M: AnalogInputModel> Sync for AnalogInput<M> {}
+
+impl<M: AnalogInputModel> Drop for AnalogInput<M> {
+    fn drop(&mut self) {
+        if let Err(err) = self.daq.destroy_acb(self.bcb) {
+            error!(device = self.device, error = %err, "DqAcbDestroy failed");
+        }
+    }
+}
@@ -1,32 +1,34 @@
+use crate::boards::OutputBoard;
+use crate::clock::{StreamClock, TimestampUnwrapper};
 use crate::config::OutputConfig;
 use crate::daq::Daq;
 use crate::results::PowerDnaError;
+use crate::stream::EventSink;
+use crate::{DaqError, StreamEvent};
 use core::marker::{Send, Sync};
 use core::mem::size_of;
 use core::ptr;
 use core::result::Result;
 use core::result::Result::{Err, Ok};
 use core::sync::atomic::{AtomicBool, Ordering};
-use core::time::Duration;
 use powerdna_sys::{event401_t_EV401_DI_CHANGE, pDQEVENT, EV401_ID};
 use std::convert::TryFrom;
 use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::time::sleep;
+use tracing::{error, warn};
 
 pub struct Dio405 {
     device: u8,
     daq: Arc<Daq>,
-    topic: String,
-    out: UnboundedSender<(String, u32)>,
+    clock: StreamClock,
+    events: EventSink,
 }
 
 impl Dio405 {
     pub fn new(
         daq: Arc<Daq>,
-        topic: String,
         board_config: &OutputConfig,
-        out: UnboundedSender<(String, u32)>,
+        clock: StreamClock,
+        events: EventSink,
     ) -> Result<Self, PowerDnaError> {
         let OutputConfig { device } = board_config;
 
@@ -34,23 +36,20 @@ impl Dio405 {
 
         Ok(Dio405 {
             device: *device,
-            topic,
             daq,
-            out,
+            clock,
+            events,
         })
     }
+}
 
-    pub async fn trigger(&self) -> Result<(), PowerDnaError> {
-        self.daq.write(self.device, 0xffffffff)?;
-        sleep(Duration::from_millis(100)).await;
-        self.daq.write(self.device, 0x0)?;
-        Ok(())
-    }
-
-    pub fn sample(&self, stop: Arc<AtomicBool>) {
+impl OutputBoard for Dio405 {
+    fn sample(&self, stop: Arc<AtomicBool>) {
         let mut p_event: pDQEVENT = ptr::null_mut();
         let mut event: u32;
         let mut timestamp: u32;
+        let mut unwrapper = TimestampUnwrapper::default();
+        let mut ticks: u64;
         let mut pos: u64;
         let mut data_slice;
         loop {
@@ -64,14 +63,14 @@ impl Dio405 {
                     };
                 }
                 Err(err) => {
-                    eprintln!("DqCmdReceiveEvent failed. Error: {:?}", err);
+                    error!(error = ?err, "DqCmdReceiveEvent failed");
                     break;
                 }
                 Ok(size) => size,
             };
             event = unsafe { (*p_event).event };
             if event != event401_t_EV401_DI_CHANGE {
-                eprintln!("Unexpected event: {}", event);
+                error!(event, "Unexpected event");
                 break;
             }
             unsafe {
@@ -81,10 +80,11 @@ impl Dio405 {
                 {
                     Ok(val) => val,
                     Err(_) => {
-                        eprintln!("Integer overflow when reading timestamp.");
+                        warn!("Integer overflow when reading timestamp");
                         break;
                     }
                 };
+                ticks = unwrapper.unwrap(timestamp);
                 let data_size: usize = (*header_ptr).size as usize / size_of::<u32>();
                 data_slice = (*header_ptr).data.as_slice(data_size);
             }
@@ -92,24 +92,33 @@ impl Dio405 {
 
             // only push buzzer event if positive edge detected
             if pos != 0 {
-                match self.out.send((self.topic.clone(), timestamp)) {
+                let event = StreamEvent::Buzzer {
+                    timestamp,
+                    ticks,
+                    unix_ns: self.clock.unix_ns(ticks),
+                };
+                match self.events.send(event) {
                     Ok(_) => (),
                     Err(err) => {
-                        eprintln!("Failed to send edge detection timestamp. Error: {}", err);
+                        error!(error = %err, "Failed to send edge detection timestamp");
                         break;
                     }
                 };
             }
         }
     }
+
+    fn write(&self, value: u32) -> Result<(), DaqError> {
+        self.daq.write(self.device, value)?;
+        Ok(())
+    }
 }
 
 impl Drop for Dio405 {
     fn drop(&mut self) {
-        match self.daq.teardown_edge_events(self.device) {
-            Err(err) => eprintln!("Failed to teardown edge events. Error: {}", err),
-            Ok(_) => (),
-        };
+        if let Err(err) = self.daq.teardown_edge_events(self.device) {
+            error!(device = self.device, error = %err, "Failed to teardown edge events")
+        }
     }
 }
 
@@ -0,0 +1,296 @@
+use crate::boards::{InputBoard, OutputBoard, RawFrame};
+use crate::clock::StreamClock;
+use crate::config::{BoardConfig, ChannelConfig, OutputConfig};
+use crate::daq::simulated::{Noise, SimulatedDaq};
+use crate::queue::BoundedSender;
+use crate::stats::BoardStats;
+use crate::stream::EventSink;
+use crate::{DaqError, StreamEvent};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+use powerdna_sys::pDQBCB;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tracing::error;
+
+/// Longest a simulated board sleeps before checking whether it has been asked to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Analogue input board producing frames in the same layout as an AI-201: each scan holds one
+/// value per channel followed by two (unused) timestamp slots.
+pub struct SimulatedInput {
+    daq: Arc<SimulatedDaq>,
+    epoch: Instant,
+    freq: u32,
+    frame_size: u32,
+    channels: Vec<ChannelConfig>,
+    noise: Mutex<Noise>,
+    stats: Arc<BoardStats>,
+    out: BoundedSender<RawFrame>,
+}
+
+impl SimulatedInput {
+    pub fn new(
+        daq: Arc<SimulatedDaq>,
+        epoch: Instant,
+        freq: u32,
+        frame_size: u32,
+        board_config: &BoardConfig,
+        stats: Arc<BoardStats>,
+        out: BoundedSender<RawFrame>,
+    ) -> Result<Self, DaqError> {
+        if freq == 0 || frame_size == 0 {
+            return Err(DaqError::ChannelConfigError);
+        }
+        Ok(SimulatedInput {
+            daq,
+            epoch,
+            freq,
+            frame_size,
+            channels: board_config.channels.clone(),
+            noise: Mutex::new(Noise::new(board_config.device as u64 + 1)),
+            stats,
+            out,
+        })
+    }
+
+    fn scan_time(&self, scan: u64) -> Duration {
+        Duration::from_secs_f64(scan as f64 / self.freq as f64)
+    }
+
+    fn generate_frame(&self, first_scan: u64) -> RawFrame {
+        let scan_size = self.channels.len() + 2;
+        let mut data = vec![0.0; self.frame_size as usize * scan_size];
+        let mut timestamps = Vec::with_capacity(self.frame_size as usize);
+        let mut noise = match self.noise.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        for (i, scan) in data.chunks_mut(scan_size).enumerate() {
+            let elapsed = self.scan_time(first_scan + i as u64);
+            let seconds = elapsed.as_secs_f64();
+            for (i, (value, channel)) in scan.iter_mut().zip(&self.channels).enumerate() {
+                *value = channel.calibrate(self.daq.value(i, seconds, &mut noise));
+            }
+            timestamps.push(self.daq.timestamp(elapsed));
+        }
+
+        (data, timestamps)
+    }
+}
+
+impl InputBoard for SimulatedInput {
+    fn sample(&self, stop: Arc<AtomicBool>) {
+        let mut scan: u64 = 0;
+        loop {
+            // wait until the hardware would have filled the frame
+            let frame_done = self.epoch + self.scan_time(scan + self.frame_size as u64);
+            loop {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                let now = Instant::now();
+                if now >= frame_done {
+                    break;
+                }
+                thread::sleep(POLL_INTERVAL.min(frame_done - now));
+            }
+
+            let frame = self.generate_frame(scan);
+            scan += self.frame_size as u64;
+
+            match self.out.send(frame) {
+                Ok(_) => {
+                    self.stats.frames.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    // the muxer thread exits by itself at the end of a timed acquisition
+                    if !stop.load(Ordering::SeqCst) {
+                        error!(error = %err, "Failed to send simulated frame to muxer thread");
+                    }
+                    break;
+                }
+            };
+        }
+    }
+
+    fn actual_freq(&self) -> f32 {
+        self.freq as f32
+    }
+
+    fn bcb(&self) -> Option<pDQBCB> {
+        None
+    }
+}
+
+/// Digital board that loops the buzzer output back as rising edge events, optionally adding
+/// periodic edges of its own.
+pub struct SimulatedOutput {
+    daq: Arc<SimulatedDaq>,
+    epoch: Instant,
+    clock: StreamClock,
+    level: AtomicU32,
+    /// When a rising edge was written, until the board's thread reports it. Events are only sent
+    /// from that thread, so that `write` never waits on the publisher.
+    edge: Mutex<Option<Duration>>,
+    events: EventSink,
+}
+
+impl SimulatedOutput {
+    pub fn new(
+        daq: Arc<SimulatedDaq>,
+        epoch: Instant,
+        _board_config: &OutputConfig,
+        clock: StreamClock,
+        events: EventSink,
+    ) -> Self {
+        SimulatedOutput {
+            daq,
+            epoch,
+            clock,
+            level: AtomicU32::new(0),
+            edge: Mutex::new(None),
+            events,
+        }
+    }
+
+    fn rising_edge(&self, elapsed: Duration) -> Result<(), DaqError> {
+        let ticks = self.daq.ticks(elapsed);
+        let event = StreamEvent::Buzzer {
+            timestamp: self.daq.timestamp(elapsed),
+            ticks,
+            unix_ns: self.clock.unix_ns(ticks),
+        };
+        self.events
+            .send(event)
+            .map_err(|_| DaqError::StreamStateError)
+    }
+}
+
+impl OutputBoard for SimulatedOutput {
+    fn sample(&self, stop: Arc<AtomicBool>) {
+        let interval = self.daq.edge_interval();
+        let mut next_edge = interval.map(|interval| self.epoch + interval);
+        loop {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let written = self.edge.lock().ok().and_then(|mut edge| edge.take());
+            if let Some(elapsed) = written {
+                if let Err(err) = self.rising_edge(elapsed) {
+                    error!(error = %err, "Failed to send edge detection timestamp");
+                    break;
+                }
+            }
+            let now = Instant::now();
+            match (next_edge, interval) {
+                (Some(edge), Some(interval)) if now >= edge => {
+                    if let Err(err) = self.rising_edge(now - self.epoch) {
+                        error!(error = %err, "Failed to send edge detection timestamp");
+                        break;
+                    }
+                    next_edge = Some(edge + interval);
+                }
+                (Some(edge), _) => thread::sleep(POLL_INTERVAL.min(edge - now)),
+                (None, _) => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    fn write(&self, value: u32) -> Result<(), DaqError> {
+        let previous = self.level.swap(value, Ordering::SeqCst);
+        // edge detection is only configured for line 0
+        if previous & 0x1 == 0 && value & 0x1 != 0 {
+            let mut edge = self.edge.lock().map_err(|_| DaqError::StreamStateError)?;
+            *edge = Some(self.epoch.elapsed());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{self, BoundedReceiver};
+    use crate::stats::StreamStats;
+    use serde_json::json;
+
+    /// A board of two channels reading a constant 1 V, the second scaled by 2, sampled at 1 kHz in
+    /// frames of 10 scans.
+    fn input(stop: &Arc<AtomicBool>) -> (SimulatedInput, BoundedReceiver<RawFrame>) {
+        let simulation = serde_json::from_value(json!({
+            "waveform": { "type": "step", "low": 1.0, "high": 1.0, "period": 1.0 },
+        }))
+        .unwrap();
+        let board_config: BoardConfig = serde_json::from_value(json!({
+            "device": 0,
+            "channels": [{ "id": 0, "gain": 1 }, { "id": 1, "gain": 1, "scale": 2.0 }],
+        }))
+        .unwrap();
+        let stats = Arc::new(StreamStats::default());
+        let (tx, rx) = queue::bounded(4, Arc::clone(stop), stats);
+        let board = SimulatedInput::new(
+            Arc::new(SimulatedDaq::new(simulation)),
+            Instant::now(),
+            1000,
+            10,
+            &board_config,
+            Arc::new(BoardStats::default()),
+            tx,
+        )
+        .unwrap();
+        (board, rx)
+    }
+
+    #[test]
+    fn lays_frames_out_like_an_ai201() {
+        let (board, _rx) = input(&Arc::new(AtomicBool::new(false)));
+        let (data, timestamps) = board.generate_frame(10);
+        assert_eq!(data.len(), 10 * 4);
+        for scan in data.chunks(4) {
+            assert_eq!(scan, [1.0, 2.0, 0.0, 0.0]);
+        }
+        let expected: Vec<u32> = (10..20).map(|scan| scan * 1000).collect();
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn samples_frames_until_stopped() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (board, mut rx) = input(&stop);
+        let board = Arc::new(board);
+        let sampling = Arc::clone(&board);
+        let cloned_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || sampling.sample(cloned_stop));
+
+        let first = rx.recv().unwrap();
+        let second = rx.recv().unwrap();
+        assert_eq!(first.1[0], 0);
+        assert_eq!(second.1[0], 10_000);
+        stop.store(true, Ordering::SeqCst);
+        thread.join().unwrap();
+        assert!(board.stats.frames.load(Ordering::Relaxed) >= 2);
+    }
+
+    #[test]
+    fn refuses_a_frame_size_of_zero() {
+        let board_config: BoardConfig =
+            serde_json::from_value(json!({ "device": 0, "channels": [] })).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, _rx) = queue::bounded(1, stop, Arc::new(StreamStats::default()));
+        let result = SimulatedInput::new(
+            Arc::new(SimulatedDaq::new(
+                serde_json::from_value(json!({})).unwrap(),
+            )),
+            Instant::now(),
+            1000,
+            0,
+            &board_config,
+            Arc::new(BoardStats::default()),
+            tx,
+        );
+        assert!(matches!(result, Err(DaqError::ChannelConfigError)));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::session;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+/// Maps a layer's timestamp ticks to wall-clock time. The hardware counters aren't necessarily
+/// reset when the acquisition starts, so the clock is anchored at the first tick it sees, taken to
+/// have happened when it was seen. Each layer needs a clock of its own.
+#[derive(Debug)]
+pub(crate) struct StreamClock {
+    rate: u64,
+    /// The first tick seen, and the host time in nanoseconds since the UNIX epoch it was seen at.
+    anchor: OnceLock<(u64, u64)>,
+}
+
+impl StreamClock {
+    pub(crate) fn new(rate: u64) -> Self {
+        StreamClock {
+            rate,
+            anchor: OnceLock::new(),
+        }
+    }
+
+    /// Anchors the clock at the given tick if it hasn't been already. Should be called with the
+    /// latest tick as soon as it has been read, before any earlier ones are converted.
+    pub(crate) fn observe(&self, ticks: u64) {
+        self.anchor(ticks, SystemTime::now());
+    }
+
+    /// Nanoseconds since the UNIX epoch at which the given tick was taken.
+    pub(crate) fn unix_ns(&self, ticks: u64) -> u64 {
+        let (anchor_ticks, anchor_ns) = self.anchor(ticks, SystemTime::now());
+        let nanos = (ticks as i128 - anchor_ticks as i128) * 1_000_000_000 / self.rate as i128;
+        (anchor_ns as i128 + nanos).clamp(0, u64::MAX as i128) as u64
+    }
+
+    fn anchor(&self, ticks: u64, seen: SystemTime) -> (u64, u64) {
+        *self.anchor.get_or_init(|| (ticks, session::unix_ns(seen)))
+    }
+}
+
+/// Extends the 32-bit hardware timestamps read from a single layer into a 64-bit counter that
+/// keeps increasing across rollovers. Timestamps must be fed in the order they were taken, and
+/// consecutive ones must be less than a full counter period apart.
+#[derive(Debug, Default)]
+pub(crate) struct TimestampUnwrapper {
+    last: Option<u64>,
+}
+
+impl TimestampUnwrapper {
+    pub(crate) fn unwrap(&mut self, raw: u32) -> u64 {
+        let ticks = match self.last {
+            Some(last) => last + raw.wrapping_sub(last as u32) as u64,
+            None => raw as u64,
+        };
+        self.last = Some(ticks);
+        ticks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn unwraps_across_rollovers() {
+        let mut unwrapper = TimestampUnwrapper::default();
+        let raw = [u32::MAX - 1, u32::MAX, 0, 1, 5];
+        let ticks: Vec<u64> = raw.iter().map(|&raw| unwrapper.unwrap(raw)).collect();
+        let base = u32::MAX as u64;
+        assert_eq!(ticks, [base - 1, base, base + 1, base + 2, base + 6]);
+    }
+
+    #[test]
+    fn keeps_counting_through_several_rollovers() {
+        let mut unwrapper = TimestampUnwrapper::default();
+        let step = 1 << 31;
+        let ticks: Vec<u64> = (0..6u64)
+            .map(|i| unwrapper.unwrap((i * step) as u32))
+            .collect();
+        assert_eq!(ticks, (0..6u64).map(|i| i * step).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn anchors_at_the_first_tick_seen() {
+        let clock = StreamClock::new(1000);
+        let seen = UNIX_EPOCH + Duration::from_secs(100);
+        clock.anchor(5_000, seen);
+        // ticks from before the anchor and after it are both relative to it
+        assert_eq!(clock.unix_ns(5_000), 100_000_000_000);
+        assert_eq!(clock.unix_ns(4_000), 99_000_000_000);
+        assert_eq!(clock.unix_ns(7_500), 102_500_000_000);
+    }
+
+    #[test]
+    fn is_only_anchored_once() {
+        let clock = StreamClock::new(1000);
+        clock.anchor(0, UNIX_EPOCH + Duration::from_secs(10));
+        clock.anchor(1_000_000, UNIX_EPOCH + Duration::from_secs(50));
+        assert_eq!(clock.unix_ns(1_000), 11_000_000_000);
+    }
+
+    #[test]
+    fn anchors_at_the_current_time_by_default() {
+        let before = session::unix_ns(SystemTime::now());
+        let clock = StreamClock::new(1000);
+        clock.observe(1 << 40);
+        let after = session::unix_ns(SystemTime::now());
+        let unix_ns = clock.unix_ns(1 << 40);
+        assert!(before <= unix_ns && unix_ns <= after);
+    }
+}
@@ -1,28 +1,834 @@
+use crate::boards::ai201::Ai201;
+use crate::boards::{AnalogInputModel, ModelSpec};
+use crate::inventory::{self, DIO405};
+use crate::ChannelMetadata;
 use crate::Gain;
-use serde::Deserialize;
+use powerdna_sys::{DQ_MAXDEVN, DQ_MAX_ACB_CLSIZE, DQ_MAX_ACB_FRAMESIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct StreamConfig {
     pub ip: String,
     pub freq: u32,
     pub frame_size: u32,
     pub boards: Vec<BoardConfig>,
     pub outputs: Vec<OutputConfig>,
+    /// Replaces the IOM with synthetic data when present. `ip` is ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub simulation: Option<SimulationConfig>,
+    /// Publish a `StreamMetadata` message describing the channel layout whenever the stream starts.
+    #[serde(default)]
+    pub metadata: bool,
+    #[serde(default)]
+    pub queue: QueueConfig,
+    /// Also write the stream's frames and buzzer events to local files while it runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<RecordingConfig>,
+    #[serde(default)]
+    pub kafka: KafkaConfig,
 }
 
-#[derive(Deserialize, Debug)]
+impl StreamConfig {
+    /// Channels in the order they appear in each `SensorFrame`.
+    pub fn channel_metadata(&self) -> Vec<ChannelMetadata> {
+        self.boards
+            .iter()
+            .flat_map(|board| {
+                board.channels.iter().map(move |channel| ChannelMetadata {
+                    name: channel
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| format!("{}:{}", board.device, channel.id)),
+                    device: board.device,
+                    channel: channel.id,
+                    gain: channel.gain.factor(),
+                    unit: channel.unit.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct BoardConfig {
     pub device: u8,
+    #[serde(rename = "type", default)]
+    pub model: BoardModel,
     pub channels: Vec<ChannelConfig>,
 }
 
-#[derive(Deserialize, Debug)]
+/// Analogue input layer fitted at a board's device number.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BoardModel {
+    #[default]
+    #[serde(rename = "AI-201")]
+    Ai201,
+}
+
+impl BoardModel {
+    pub(crate) fn spec(&self) -> &'static ModelSpec {
+        match self {
+            BoardModel::Ai201 => &Ai201::SPEC,
+        }
+    }
+
+    pub(crate) fn from_model_id(model_id: u16) -> Option<BoardModel> {
+        [BoardModel::Ai201]
+            .into_iter()
+            .find(|model| model.spec().model_id == model_id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ChannelConfig {
     pub id: u8,
     pub gain: Gain,
+    /// Defaults to `<device>:<id>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Engineering unit of the calibrated value.
+    #[serde(default = "default_unit")]
+    pub unit: String,
+    /// Linear calibration applied to the measured voltage: `value = volts * scale + offset`.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    /// Polynomial coefficients, lowest order first. Replaces `scale` and `offset` when given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub polynomial: Option<Vec<f64>>,
+}
+
+impl ChannelConfig {
+    /// Converts a measured voltage into engineering units.
+    pub fn calibrate(&self, volts: f64) -> f64 {
+        match &self.polynomial {
+            Some(coefficients) => coefficients
+                .iter()
+                .rev()
+                .fold(0.0, |value, coefficient| value * volts + coefficient),
+            None => volts * self.scale + self.offset,
+        }
+    }
+}
+
+fn default_unit() -> String {
+    String::from("V")
 }
 
-#[derive(Deserialize, Debug)]
+fn default_scale() -> f64 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct OutputConfig {
     pub device: u8,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SimulationConfig {
+    #[serde(default)]
+    pub waveform: Waveform,
+    /// Period of the simulated rising edges on DIO line 0. Edges are only produced by the buzzer
+    /// if omitted.
+    #[serde(default)]
+    pub edge_interval_ms: Option<u64>,
+}
+
+/// Sizes the queues that frames and events pass through on their way from the boards to the
+/// publisher.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueueConfig {
+    /// Frames (or events) each queue holds.
+    #[serde(default = "default_queue_depth")]
+    pub depth: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            depth: default_queue_depth(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+fn default_queue_depth() -> usize {
+    64
+}
+
+/// What happens to a frame or event that arrives at a full publisher queue. The queues between the
+/// boards and the muxer always block, so that the boards' frames stay aligned.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Wait for space. Once the board's own buffer fills up, the IOM starts reporting lost packets.
+    #[default]
+    Block,
+    /// Discard it and count it as dropped.
+    DropNewest,
+}
+
+/// Where the stream's messages are published and how they are keyed. Both topics default to the
+/// stream's name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KafkaConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor_topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_topic: Option<String>,
+    /// Message key, in which `{stream}`, `{kind}` (`sensor` or `event`), `{event}` (e.g.
+    /// `BuzzerEvent`), `{session}` and `{board}` (device numbers joined with `+`) are replaced.
+    #[serde(default = "default_key")]
+    pub key: String,
+    #[serde(default)]
+    pub partition: PartitionStrategy,
+    /// Added to every message alongside the standard headers.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            sensor_topic: None,
+            event_topic: None,
+            key: default_key(),
+            partition: PartitionStrategy::default(),
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_key() -> String {
+    String::from("{stream}")
+}
+
+/// Placeholders accepted in `KafkaConfig::key`.
+pub const KEY_PLACEHOLDERS: [&str; 5] = ["stream", "kind", "event", "session", "board"];
+
+/// Headers set on every message, which `KafkaConfig::headers` can't override.
+pub const STANDARD_HEADERS: [&str; 3] = ["schema-version", "content-type", "stream"];
+
+/// How messages are assigned to partitions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "strategy", rename_all = "lowercase")]
+pub enum PartitionStrategy {
+    /// By the hash of the message key, so that messages with the same key stay in order.
+    #[default]
+    Key,
+    /// Sent without a key and spread across the partitions by the producer.
+    Random,
+    Fixed {
+        partition: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordingConfig {
+    pub directory: String,
+    #[serde(default)]
+    pub format: RecordingFormat,
+    /// Start a new file once the current one reaches this size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_bytes: Option<u64>,
+    /// Start a new file once the current one has been open this long.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    /// One row per scan, preceded by `#` comment lines describing the recording.
+    #[default]
+    Csv,
+    /// One JSON object per frame or event, preceded by a `StreamHeader` object.
+    Jsonl,
+}
+
+/// Synthetic signal applied to every simulated analogue channel. Sine waves are phase shifted by
+/// channel so that channels can be told apart.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Waveform {
+    Sine {
+        amplitude: f64,
+        frequency: f64,
+        #[serde(default)]
+        offset: f64,
+    },
+    Noise {
+        amplitude: f64,
+        #[serde(default)]
+        offset: f64,
+    },
+    Step {
+        low: f64,
+        high: f64,
+        period: f64,
+    },
+}
+
+impl Default for Waveform {
+    fn default() -> Self {
+        Waveform::Sine {
+            amplitude: 1.0,
+            frequency: 1.0,
+            offset: 0.0,
+        }
+    }
+}
+
+/// A single problem found by `validate`, located by its JSON path within the config file.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigProblem {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Checks the stream configs for mistakes that would otherwise only surface once the hardware
+/// rejects them. Every problem is reported rather than just the first.
+pub fn validate(streams: &HashMap<String, StreamConfig>) -> Result<(), Vec<ConfigProblem>> {
+    let mut problems = Vec::new();
+    let mut ips: HashMap<&str, &str> = HashMap::new();
+
+    let mut names: Vec<&String> = streams.keys().collect();
+    names.sort();
+
+    for name in names {
+        let stream = &streams[name];
+
+        if stream.simulation.is_none() {
+            match ips.get(stream.ip.as_str()) {
+                Some(other) => problems.push(ConfigProblem {
+                    path: format!("{}.ip", name),
+                    message: format!("IOM {} is already used by stream '{}'", stream.ip, other),
+                }),
+                None => {
+                    ips.insert(&stream.ip, name);
+                }
+            }
+        }
+
+        validate_stream(name, stream, &mut problems);
+    }
+
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems),
+    }
+}
+
+pub(crate) fn join_problems(problems: &[ConfigProblem]) -> String {
+    problems
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// Checks the stream's boards against the layer models reported by its IOM, indexed by device
+/// number, so that a board in the wrong slot is caught before it is configured.
+pub fn verify_layers(
+    name: &str,
+    stream: &StreamConfig,
+    models: &[u16],
+) -> Result<(), Vec<ConfigProblem>> {
+    let boards = stream.boards.iter().enumerate().map(|(i, board)| {
+        (
+            format!("{}.boards[{}].device", name, i),
+            board.device,
+            board.model.spec().model_id,
+        )
+    });
+    let outputs = stream.outputs.iter().enumerate().map(|(i, output)| {
+        (
+            format!("{}.outputs[{}].device", name, i),
+            output.device,
+            DIO405,
+        )
+    });
+
+    let problems: Vec<ConfigProblem> = boards
+        .chain(outputs)
+        .filter_map(|(path, device, expected)| {
+            let message = match models.get(device as usize) {
+                None | Some(0) => format!(
+                    "no layer is fitted at device {}, expected {}",
+                    device,
+                    inventory::model_name(expected)
+                ),
+                Some(&model) if model != expected => format!(
+                    "device {} is {}, expected {}",
+                    device,
+                    inventory::model_name(model),
+                    inventory::model_name(expected)
+                ),
+                Some(_) => return None,
+            };
+            Some(ConfigProblem { path, message })
+        })
+        .collect();
+
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems),
+    }
+}
+
+fn validate_stream(name: &str, stream: &StreamConfig, problems: &mut Vec<ConfigProblem>) {
+    let mut problem =
+        |path: String, message: String| problems.push(ConfigProblem { path, message });
+
+    // the slowest board limits the whole stream
+    let max_freq = stream
+        .boards
+        .iter()
+        .map(|board| board.model.spec())
+        .min_by_key(|spec| spec.max_freq);
+    if stream.freq == 0 {
+        problem(
+            format!("{}.freq", name),
+            String::from("must be greater than 0"),
+        );
+    } else if let Some(spec) = max_freq.filter(|spec| stream.freq > spec.max_freq) {
+        problem(
+            format!("{}.freq", name),
+            format!(
+                "exceeds the {}'s maximum of {} Hz",
+                spec.name(),
+                spec.max_freq
+            ),
+        );
+    }
+    if stream.frame_size == 0 {
+        problem(
+            format!("{}.frame_size", name),
+            String::from("must be greater than 0"),
+        );
+    } else if stream.frame_size > DQ_MAX_ACB_FRAMESIZE {
+        problem(
+            format!("{}.frame_size", name),
+            format!("exceeds the ACB maximum of {} scans", DQ_MAX_ACB_FRAMESIZE),
+        );
+    }
+    if stream.frame_size > u16::MAX as u32 {
+        problem(
+            format!("{}.frame_size", name),
+            format!("a SensorFrame holds at most {} samples", u16::MAX),
+        );
+    }
+    let total_channels: usize = stream.boards.iter().map(|board| board.channels.len()).sum();
+    if total_channels > u8::MAX as usize {
+        problem(
+            format!("{}.boards", name),
+            format!("a SensorFrame holds at most {} channels", u8::MAX),
+        );
+    }
+    if stream.boards.is_empty() {
+        problem(
+            format!("{}.boards", name),
+            String::from("at least one input board is required"),
+        );
+    }
+
+    if stream.queue.depth == 0 {
+        problem(
+            format!("{}.queue.depth", name),
+            String::from("must be greater than 0"),
+        );
+    }
+    if let Some(recording) = &stream.recording {
+        if recording.directory.trim().is_empty() {
+            problem(
+                format!("{}.recording.directory", name),
+                String::from("must not be empty"),
+            );
+        }
+        if recording.max_file_bytes == Some(0) {
+            problem(
+                format!("{}.recording.max_file_bytes", name),
+                String::from("must be greater than 0"),
+            );
+        }
+        if recording.max_file_secs == Some(0) {
+            problem(
+                format!("{}.recording.max_file_secs", name),
+                String::from("must be greater than 0"),
+            );
+        }
+    }
+
+    if let Some(simulation) = &stream.simulation {
+        if simulation.edge_interval_ms == Some(0) {
+            problem(
+                format!("{}.simulation.edge_interval_ms", name),
+                String::from("must be greater than 0"),
+            );
+        }
+        if let Waveform::Step { period, .. } = simulation.waveform {
+            if !(period.is_finite() && period > 0.0) {
+                problem(
+                    format!("{}.simulation.waveform.period", name),
+                    String::from("must be a positive number of seconds"),
+                );
+            }
+        }
+    }
+
+    validate_kafka(name, &stream.kafka, &mut problem);
+
+    let mut devices: HashSet<u8> = HashSet::new();
+    let board_devices = stream
+        .boards
+        .iter()
+        .enumerate()
+        .map(|(i, board)| (format!("{}.boards[{}].device", name, i), board.device));
+    let output_devices = stream
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(i, output)| (format!("{}.outputs[{}].device", name, i), output.device));
+    for (path, device) in board_devices.chain(output_devices) {
+        if device as u32 >= DQ_MAXDEVN {
+            problem(
+                path,
+                format!("device {} is out of range (0-{})", device, DQ_MAXDEVN - 1),
+            );
+        } else if !devices.insert(device) {
+            problem(
+                path,
+                format!("device {} is configured more than once", device),
+            );
+        }
+    }
+
+    let mut channel_names: HashSet<String> = HashSet::new();
+    for (i, board) in stream.boards.iter().enumerate() {
+        let path = format!("{}.boards[{}]", name, i);
+        let spec = board.model.spec();
+        if board.channels.is_empty() {
+            problem(
+                format!("{}.channels", path),
+                String::from("at least one channel is required"),
+            );
+        }
+        // 2 extra entries in the channel list for the timestamp
+        if board.channels.len() + 2 > DQ_MAX_ACB_CLSIZE as usize {
+            problem(
+                format!("{}.channels", path),
+                format!(
+                    "the ACB channel list holds at most {} channels",
+                    DQ_MAX_ACB_CLSIZE - 2
+                ),
+            );
+        }
+        let mut ids: HashSet<u8> = HashSet::new();
+        for (j, channel) in board.channels.iter().enumerate() {
+            let path = format!("{}.channels[{}].id", path, j);
+            if channel.id as u32 >= spec.channels {
+                problem(
+                    path,
+                    format!(
+                        "channel {} is out of range for the {} (0-{})",
+                        channel.id,
+                        spec.name(),
+                        spec.channels - 1
+                    ),
+                );
+            } else if !ids.insert(channel.id) {
+                problem(
+                    path,
+                    format!("channel {} is configured more than once", channel.id),
+                );
+            }
+
+            let path = format!("{}.boards[{}].channels[{}]", name, i, j);
+            let channel_name = channel
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{}:{}", board.device, channel.id));
+            if !channel_names.insert(channel_name.clone()) {
+                problem(
+                    format!("{}.name", path),
+                    format!("channel name '{}' is used more than once", channel_name),
+                );
+            }
+            if spec.gain_code(channel.gain).is_none() {
+                let gains: Vec<String> = spec
+                    .gains
+                    .iter()
+                    .map(|(factor, _)| factor.to_string())
+                    .collect();
+                problem(
+                    format!("{}.gain", path),
+                    format!("the {} supports gains of {}", spec.name(), gains.join(", ")),
+                );
+            }
+            if !channel.scale.is_finite() || !channel.offset.is_finite() {
+                problem(
+                    path.clone(),
+                    String::from("scale and offset must be finite"),
+                );
+            }
+            if let Some(coefficients) = &channel.polynomial {
+                if coefficients.is_empty() || coefficients.iter().any(|c| !c.is_finite()) {
+                    problem(
+                        format!("{}.polynomial", path),
+                        String::from("expected at least one finite coefficient"),
+                    );
+                }
+                if channel.scale != 1.0 || channel.offset != 0.0 {
+                    problem(
+                        path,
+                        String::from("polynomial cannot be combined with scale and offset"),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Topic names are limited to what Kafka accepts.
+fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= 249
+        && topic != "."
+        && topic != ".."
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+fn validate_kafka(name: &str, kafka: &KafkaConfig, problem: &mut impl FnMut(String, String)) {
+    let topics = [
+        ("sensor_topic", &kafka.sensor_topic),
+        ("event_topic", &kafka.event_topic),
+    ];
+    for (field, topic) in topics {
+        if let Some(topic) = topic {
+            if !valid_topic(topic) {
+                problem(
+                    format!("{}.kafka.{}", name, field),
+                    format!(
+                        "'{}' isn't a valid topic name (letters, digits, '.', '_' and '-')",
+                        topic
+                    ),
+                );
+            }
+        }
+    }
+
+    let mut rest = kafka.key.as_str();
+    while let Some(start) = rest.find('{') {
+        let placeholder = match rest[start..].find('}') {
+            Some(end) => &rest[start + 1..start + end],
+            None => {
+                problem(
+                    format!("{}.kafka.key", name),
+                    String::from("unterminated placeholder"),
+                );
+                break;
+            }
+        };
+        if !KEY_PLACEHOLDERS.contains(&placeholder) {
+            problem(
+                format!("{}.kafka.key", name),
+                format!(
+                    "unknown placeholder {{{}}}, expected one of {{{}}}",
+                    placeholder,
+                    KEY_PLACEHOLDERS.join("}, {")
+                ),
+            );
+        }
+        rest = &rest[start + placeholder.len() + 2..];
+    }
+
+    if let PartitionStrategy::Fixed { partition } = kafka.partition {
+        if partition < 0 {
+            problem(
+                format!("{}.kafka.partition.partition", name),
+                String::from("must not be negative"),
+            );
+        }
+    }
+
+    for header in kafka.headers.keys() {
+        if header.is_empty() {
+            problem(
+                format!("{}.kafka.headers", name),
+                String::from("header names must not be empty"),
+            );
+        } else if STANDARD_HEADERS.contains(&header.to_lowercase().as_str()) {
+            problem(
+                format!("{}.kafka.headers.{}", name, header),
+                String::from("is set by Daiquiri"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stream(overrides: serde_json::Value) -> StreamConfig {
+        let mut config = json!({
+            "ip": "192.168.100.2",
+            "freq": 1000,
+            "frame_size": 100,
+            "boards": [{ "device": 0, "channels": [{ "id": 0, "gain": 1 }] }],
+            "outputs": [],
+        });
+        for (key, value) in overrides.as_object().unwrap() {
+            config[key] = value.clone();
+        }
+        serde_json::from_value(config).unwrap()
+    }
+
+    /// Paths of the problems found in a single stream named `s`.
+    fn problems(config: StreamConfig) -> Vec<String> {
+        let streams = HashMap::from([(String::from("s"), config)]);
+        match validate(&streams) {
+            Ok(_) => vec![],
+            Err(problems) => problems.into_iter().map(|problem| problem.path).collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_a_minimal_stream() {
+        assert_eq!(problems(stream(json!({}))), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejects_a_zero_edge_interval() {
+        let config = stream(json!({ "simulation": { "edge_interval_ms": 0 } }));
+        assert_eq!(problems(config), ["s.simulation.edge_interval_ms"]);
+    }
+
+    #[test]
+    fn rejects_a_step_without_a_positive_period() {
+        for period in [0.0, -1.0] {
+            let config = stream(json!({
+                "simulation": { "waveform": { "type": "step", "low": 0, "high": 1, "period": period } }
+            }));
+            assert_eq!(problems(config), ["s.simulation.waveform.period"]);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_gains() {
+        let config = stream(json!({
+            "boards": [{ "device": 0, "channels": [{ "id": 0, "gain": 3 }] }]
+        }));
+        assert_eq!(problems(config), ["s.boards[0].channels[0].gain"]);
+    }
+
+    #[test]
+    fn rejects_channels_out_of_range_or_repeated() {
+        let config = stream(json!({
+            "boards": [{ "device": 0, "channels": [
+                { "id": 0, "gain": 1 },
+                { "id": 0, "gain": 1, "name": "again" },
+                { "id": 200, "gain": 1 },
+            ] }]
+        }));
+        assert_eq!(
+            problems(config),
+            ["s.boards[0].channels[1].id", "s.boards[0].channels[2].id"]
+        );
+    }
+
+    #[test]
+    fn rejects_repeated_devices_and_channel_names() {
+        let config = stream(json!({
+            "boards": [
+                { "device": 0, "channels": [{ "id": 0, "gain": 1, "name": "a" }] },
+                { "device": 0, "channels": [{ "id": 1, "gain": 1, "name": "a" }] },
+            ]
+        }));
+        assert_eq!(
+            problems(config),
+            ["s.boards[1].device", "s.boards[1].channels[0].name"]
+        );
+    }
+
+    #[test]
+    fn rejects_an_iom_shared_by_two_streams() {
+        let streams = HashMap::from([
+            (String::from("a"), stream(json!({}))),
+            (String::from("b"), stream(json!({}))),
+        ]);
+        let problems = validate(&streams).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, "b.ip");
+    }
+
+    #[test]
+    fn checks_kafka_topics_and_key_placeholders() {
+        let config = stream(json!({
+            "kafka": { "sensor_topic": "sensor data", "key": "{stream}-{session}-{board}-{nope}" }
+        }));
+        assert_eq!(problems(config), ["s.kafka.sensor_topic", "s.kafka.key"]);
+        let config = stream(json!({ "kafka": { "key": "{stream" } }));
+        assert_eq!(problems(config), ["s.kafka.key"]);
+    }
+
+    #[test]
+    fn checks_kafka_partitions_and_headers() {
+        let config = stream(json!({
+            "kafka": {
+                "partition": { "strategy": "fixed", "partition": -1 },
+                "headers": { "": "x", "Stream": "y", "site": "lab" },
+            }
+        }));
+        assert_eq!(
+            problems(config),
+            [
+                "s.kafka.partition.partition",
+                "s.kafka.headers",
+                "s.kafka.headers.Stream"
+            ]
+        );
+    }
+
+    #[test]
+    fn verifies_fitted_layers() {
+        let config = stream(json!({
+            "boards": [
+                { "device": 0, "channels": [{ "id": 0, "gain": 1 }] },
+                { "device": 1, "channels": [{ "id": 0, "gain": 1 }] },
+            ],
+            "outputs": [{ "device": 2 }, { "device": 5 }],
+        }));
+        let ai201 = BoardModel::Ai201.spec().model_id;
+        assert!(verify_layers("s", &config, &[ai201, ai201, DIO405]).is_err());
+        let problems: Vec<String> = verify_layers("s", &config, &[ai201, DIO405, 0])
+            .unwrap_err()
+            .into_iter()
+            .map(|problem| problem.path)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "s.boards[1].device",
+                "s.outputs[0].device",
+                "s.outputs[1].device"
+            ]
+        );
+        let config = stream(json!({ "outputs": [{ "device": 1 }] }));
+        assert!(verify_layers("s", &config, &[ai201, DIO405]).is_ok());
+    }
+}
@@ -1,20 +1,138 @@
+pub mod simulated;
+
+use crate::boards::dio405::Dio405;
+use crate::boards::{self, InputBoard, OutputBoard, RawFrame};
+use crate::clock::StreamClock;
+use crate::config::{self, BoardConfig, OutputConfig, StreamConfig};
 use crate::engine::{DqEngine, InterfaceType};
+use crate::inventory::{self, Inventory, Layer};
+use crate::queue::BoundedSender;
 use crate::results::{PowerDnaError, PowerDnaSuccess};
+use crate::stats::BoardStats;
+use crate::stream::EventSink;
+use crate::DaqError;
 use powerdna_sys::{
     event401_t_EV401_CLEAR, event401_t_EV401_DI_CHANGE, pDATACONV, pDQBCB, pDQEVENT, DqAcbDestroy,
-    DqAddIOMPort, DqAdv40xConfigEvents, DqAdv40xWrite, DqCloseIOM, DqCmdReadStatus,
-    DqCmdReceiveEvent, DqCmdSetCfg, DqCmdSetMode, DqConvFillConvData, DqConvGetDataConv, DqNtohl,
-    DqOpenIOM, DqRtAsyncEnableEvents, DQSETCFG, DQ_IOMODE_CFG, DQ_IOMODE_OPS, DQ_LASTDEV,
-    DQ_LN_ACTIVE, DQ_LN_ENABLED, DQ_LN_MAPPED, DQ_MAXDEVN, DQ_SS0IN, DQ_UDP_DAQ_PORT,
-    DQ_UDP_DAQ_PORT_ASYNC, STS_FW, STS_FW_OPER_MODE,
+    DqAddIOMPort, DqAdv40xConfigEvents, DqAdv40xWrite, DqCloseIOM, DqCmdCheckAlive, DqCmdEcho,
+    DqCmdGetCapabilities, DqCmdGetName, DqCmdReadStatus, DqCmdReceiveEvent, DqCmdSetCfg,
+    DqCmdSetMode, DqConvFillConvData, DqConvGetDataConv, DqNtohl, DqOpenIOM, DqRtAsyncEnableEvents,
+    DQRDCFG, DQSETCFG, DQ_IOMODE_CFG, DQ_IOMODE_OPS, DQ_LASTDEV, DQ_LN_ACTIVE, DQ_LN_ENABLED,
+    DQ_LN_MAPPED, DQ_MAXDEVN, DQ_MAX_INFO_SIZE, DQ_SS0IN, DQ_UDP_DAQ_PORT, DQ_UDP_DAQ_PORT_ASYNC,
+    STS_FW, STS_FW_OPER_MODE,
 };
 use std::ffi::CString;
+use std::mem;
+use std::os::raw::{c_char, c_int};
 use std::ptr;
 use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info};
 
 const TIMEOUT: u32 = 200;
 
+/// Capability reports are fetched in chunks; this bounds how many are read per layer.
+const MAX_CAPABILITY_CHUNKS: usize = 16;
+
+/// Rate of the layers' timestamp counters, which tick once per microsecond unless configured
+/// otherwise (`DQ_LN_1us_TIMESTAMP`).
+pub(crate) const TIMESTAMP_RATE: u64 = 1_000_000;
+
+/// Where a stream's samples come from: an IOM, or a simulation of one. A backend opens the boards
+/// that the stream's threads then drive, so that nothing else needs to know which it is.
+pub trait Backend: Send + Sync {
+    /// IP address of the IOM, for logging.
+    fn address(&self) -> &str;
+
+    /// Checks that the IOM still answers.
+    fn ping(&self) -> Result<(), PowerDnaError>;
+
+    /// Ticks per second of the raw timestamps produced by the backend's boards.
+    fn timestamp_rate(&self) -> u64;
+
+    /// Name of an input board's layer, for logging.
+    fn input_name(&self, board_config: &BoardConfig) -> String;
+
+    /// Configures an input board, ready for its thread to sample it.
+    fn open_input(self: Arc<Self>, setup: InputSetup) -> Result<Arc<dyn InputBoard>, DaqError>;
+
+    /// Name of the output boards' layer, for logging.
+    fn output_name(&self) -> &'static str;
+
+    /// Configures an output board, ready for its thread to report its edges.
+    fn open_output(self: Arc<Self>, setup: OutputSetup) -> Result<Arc<dyn OutputBoard>, DaqError>;
+}
+
+/// A stream's connection to its backend, shared with its boards.
+pub type DaqBackend = Arc<dyn Backend>;
+
+/// What an input board is opened with.
+pub struct InputSetup<'a> {
+    pub(crate) board_config: &'a BoardConfig,
+    pub(crate) freq: u32,
+    pub(crate) frame_size: u32,
+    /// When the acquisition started, for simulated boards to time their frames from.
+    pub(crate) epoch: Instant,
+    pub(crate) stats: Arc<BoardStats>,
+    pub(crate) out: BoundedSender<RawFrame>,
+}
+
+/// What an output board is opened with.
+pub struct OutputSetup<'a> {
+    pub(crate) board_config: &'a OutputConfig,
+    /// When the acquisition started, for simulated boards to time their edges from.
+    pub(crate) epoch: Instant,
+    pub(crate) clock: StreamClock,
+    pub(crate) events: EventSink,
+}
+
+impl Backend for Daq {
+    fn address(&self) -> &str {
+        &self.ip
+    }
+
+    /// Round trip to the IOM over the command connection, used to watch IOMs that aren't
+    /// streaming.
+    fn ping(&self) -> Result<(), PowerDnaError> {
+        let mut response: u32 = 0;
+        parse_err!(DqCmdCheckAlive(self.handle, &mut response))?;
+        Ok(())
+    }
+
+    fn timestamp_rate(&self) -> u64 {
+        TIMESTAMP_RATE
+    }
+
+    fn input_name(&self, board_config: &BoardConfig) -> String {
+        board_config.model.spec().name()
+    }
+
+    fn open_input(self: Arc<Self>, setup: InputSetup) -> Result<Arc<dyn InputBoard>, DaqError> {
+        boards::open_input(
+            self,
+            setup.freq,
+            setup.frame_size,
+            setup.board_config,
+            setup.stats,
+            setup.out,
+        )
+    }
+
+    fn output_name(&self) -> &'static str {
+        "DIO-405"
+    }
+
+    fn open_output(self: Arc<Self>, setup: OutputSetup) -> Result<Arc<dyn OutputBoard>, DaqError> {
+        Ok(Arc::new(Dio405::new(
+            self,
+            setup.board_config,
+            setup.clock,
+            setup.events,
+        )?))
+    }
+}
+
 pub struct Daq {
+    ip: String,
     handle: i32,
     async_handle: i32,
     dqe: Arc<DqEngine>,
@@ -49,7 +167,10 @@ impl Daq {
             TIMEOUT
         ))?;
 
+        info!(iom = %ip, "Connected to IOM");
+
         Ok(Daq {
+            ip,
             handle,
             async_handle,
             dqe,
@@ -69,6 +190,16 @@ impl Daq {
     }
 
     pub(crate) fn enter_config_mode(&self, device: u8) -> Result<(), PowerDnaError> {
+        let status_buffer = self.read_status(device)?;
+
+        if status_buffer[STS_FW as usize] & STS_FW_OPER_MODE != 0 {
+            parse_err!(DqCmdSetMode(self.handle, DQ_IOMODE_CFG, 1 << device))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_status(&self, device: u8) -> Result<[u32; DQ_MAXDEVN as usize + 1], PowerDnaError> {
         let devices: u8 = device | (DQ_LASTDEV as u8);
         let mut num_devices: u32 = 1;
         let mut status_buffer: [u32; DQ_MAXDEVN as usize + 1] = [0; DQ_MAXDEVN as usize + 1];
@@ -83,17 +214,102 @@ impl Daq {
             &mut status_size
         ))?;
 
-        if status_buffer[STS_FW as usize] & STS_FW_OPER_MODE != 0 {
-            parse_err!(DqCmdSetMode(self.handle, DQ_IOMODE_CFG, 1 << device))?;
+        Ok(status_buffer)
+    }
+
+    fn echo(&self) -> Result<DQRDCFG, PowerDnaError> {
+        let mut config: DQRDCFG = unsafe { mem::zeroed() };
+        parse_err!(DqCmdEcho(self.handle, &mut config))?;
+        Ok(config)
+    }
+
+    /// Model number of the layer at each device number, or 0 where no layer is fitted.
+    pub fn layer_models(&self) -> Result<Vec<u16>, PowerDnaError> {
+        let models = self.echo()?.devmod;
+        Ok(models.to_vec())
+    }
+
+    /// Fails with every configured board that isn't fitted to the IOM or is of the wrong model.
+    pub fn verify_layers(&self, name: &str, config: &StreamConfig) -> Result<(), DaqError> {
+        let models = self.layer_models()?;
+        config::verify_layers(name, config, &models)
+            .map_err(|problems| DaqError::HardwareMismatchError { problems })
+    }
+
+    /// Asks the IOM what is fitted to it. Slots without a layer are left out.
+    pub fn inventory(&self) -> Result<Inventory, PowerDnaError> {
+        let config = self.echo()?;
+
+        let mut name = vec![0 as c_char; 128];
+        parse_err!(DqCmdGetName(
+            self.handle,
+            name.len() as u32,
+            name.as_mut_ptr()
+        ))?;
+
+        // copied out of the packed struct before use
+        let models = config.devmod;
+        let options = config.option;
+        let mut layers = Vec::new();
+        for (device, (&model_id, &option)) in models.iter().zip(options.iter()).enumerate() {
+            if model_id == 0 {
+                continue;
+            }
+            let device = device as u8;
+            let status = self.read_status(device)?;
+            let firmware_status = status[STS_FW as usize];
+            layers.push(Layer {
+                device,
+                model: inventory::model_name(model_id),
+                model_id,
+                option,
+                channels: inventory::channels(model_id),
+                firmware_status,
+                operating: firmware_status & STS_FW_OPER_MODE != 0,
+                capabilities: self.capabilities(device)?,
+            });
         }
 
-        Ok(())
+        let model = config.model;
+        Ok(Inventory {
+            ip: self.ip.clone(),
+            name: c_string(&name),
+            model: format!("0x{:x}", model),
+            serial: config.sernum,
+            manufactured: config.mfgdate,
+            calibrated: config.caldate,
+            layers,
+        })
+    }
+
+    fn capabilities(&self, device: u8) -> Result<Vec<String>, PowerDnaError> {
+        let mut text = String::new();
+        for _ in 0..MAX_CAPABILITY_CHUNKS {
+            let mut info = vec![0 as c_char; DQ_MAX_INFO_SIZE as usize];
+            let mut more: c_int = 0;
+            parse_err!(DqCmdGetCapabilities(
+                self.handle,
+                device,
+                &mut more,
+                info.as_mut_ptr()
+            ))?;
+            text.push_str(&c_string(&info));
+            if more == 0 {
+                break;
+            }
+        }
+        Ok(text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect())
     }
 
     pub(crate) fn get_data_converter(
         &self,
         device: u8,
-        channels: &Vec<u32>,
+        channels: &[u32],
     ) -> Result<pDATACONV, PowerDnaError> {
         parse_err!(DqConvFillConvData(
             self.handle,
@@ -180,19 +396,23 @@ impl Daq {
     }
 }
 
+/// Reads a NUL-terminated string out of a buffer filled in by the PowerDNA library.
+fn c_string(buffer: &[c_char]) -> String {
+    let bytes: Vec<u8> = buffer
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
 impl Drop for Daq {
     fn drop(&mut self) {
-        match parse_err!(DqCloseIOM(self.async_handle)) {
-            Err(err) => {
-                eprintln!("Async DqCloseIOM failed. Error: {:?}", err);
-            }
-            Ok(_) => {}
+        if let Err(err) = parse_err!(DqCloseIOM(self.async_handle)) {
+            error!(iom = %self.ip, error = ?err, "Async DqCloseIOM failed");
+        }
+        if let Err(err) = parse_err!(DqCloseIOM(self.handle)) {
+            error!(iom = %self.ip, error = ?err, "DqCloseIOM failed");
         }
-        match parse_err!(DqCloseIOM(self.handle)) {
-            Err(err) => {
-                eprintln!("DqCloseIOM failed. Error: {:?}", err);
-            }
-            Ok(_) => {}
-        };
     }
 }
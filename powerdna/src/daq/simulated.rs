@@ -0,0 +1,184 @@
+use crate::boards::simulated::{SimulatedInput, SimulatedOutput};
+use crate::boards::{InputBoard, OutputBoard};
+use crate::config::{BoardConfig, SimulationConfig, Waveform};
+use crate::daq::{Backend, InputSetup, OutputSetup};
+use crate::results::PowerDnaError;
+use crate::DaqError;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Rate of the simulated IOM's timestamp counter, in ticks per second.
+pub(crate) const TIMESTAMP_RATE: u64 = 1_000_000;
+
+/// Stand-in for an IOM that produces synthetic waveforms instead of talking to hardware.
+pub struct SimulatedDaq {
+    config: SimulationConfig,
+}
+
+impl SimulatedDaq {
+    pub fn new(config: SimulationConfig) -> Self {
+        SimulatedDaq { config }
+    }
+
+    pub(crate) fn edge_interval(&self) -> Option<Duration> {
+        self.config.edge_interval_ms.map(Duration::from_millis)
+    }
+
+    /// Value of the given channel `seconds` after the start of the acquisition.
+    pub(crate) fn value(&self, channel: usize, seconds: f64, noise: &mut Noise) -> f64 {
+        match self.config.waveform {
+            Waveform::Sine {
+                amplitude,
+                frequency,
+                offset,
+            } => {
+                let phase = channel as f64 * PI / 8.0;
+                offset + amplitude * (2.0 * PI * frequency * seconds + phase).sin()
+            }
+            Waveform::Noise { amplitude, offset } => offset + amplitude * noise.next(),
+            Waveform::Step { low, high, period } => match (seconds / period) % 2.0 < 1.0 {
+                true => low,
+                false => high,
+            },
+        }
+    }
+
+    /// Converts an offset from the start of the acquisition into timestamp ticks.
+    pub(crate) fn ticks(&self, elapsed: Duration) -> u64 {
+        (elapsed.as_nanos() * TIMESTAMP_RATE as u128 / 1_000_000_000) as u64
+    }
+
+    /// Converts an offset from the start of the acquisition into a raw 32-bit timestamp, wrapping
+    /// the same way the hardware counter does.
+    pub(crate) fn timestamp(&self, elapsed: Duration) -> u32 {
+        self.ticks(elapsed) as u32
+    }
+}
+
+impl Backend for SimulatedDaq {
+    fn address(&self) -> &str {
+        "simulated"
+    }
+
+    /// A simulated IOM always answers.
+    fn ping(&self) -> Result<(), PowerDnaError> {
+        Ok(())
+    }
+
+    fn timestamp_rate(&self) -> u64 {
+        TIMESTAMP_RATE
+    }
+
+    fn input_name(&self, _board_config: &BoardConfig) -> String {
+        String::from("simulated")
+    }
+
+    fn open_input(self: Arc<Self>, setup: InputSetup) -> Result<Arc<dyn InputBoard>, DaqError> {
+        Ok(Arc::new(SimulatedInput::new(
+            self,
+            setup.epoch,
+            setup.freq,
+            setup.frame_size,
+            setup.board_config,
+            setup.stats,
+            setup.out,
+        )?))
+    }
+
+    fn output_name(&self) -> &'static str {
+        "simulated"
+    }
+
+    fn open_output(self: Arc<Self>, setup: OutputSetup) -> Result<Arc<dyn OutputBoard>, DaqError> {
+        Ok(Arc::new(SimulatedOutput::new(
+            self,
+            setup.epoch,
+            setup.board_config,
+            setup.clock,
+            setup.events,
+        )))
+    }
+}
+
+/// xorshift64* generator; good enough for test signals and avoids pulling in a RNG crate.
+pub(crate) struct Noise {
+    state: u64,
+}
+
+impl Noise {
+    pub(crate) fn new(seed: u64) -> Self {
+        Noise {
+            state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    /// Uniformly distributed value in [-1, 1).
+    pub(crate) fn next(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let value = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (value >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn daq(simulation: serde_json::Value) -> SimulatedDaq {
+        SimulatedDaq::new(serde_json::from_value(simulation).unwrap())
+    }
+
+    fn close(actual: f64, expected: f64) -> bool {
+        (actual - expected).abs() < 1e-9
+    }
+
+    #[test]
+    fn shifts_each_channels_sine_wave() {
+        let sine = daq(json!({
+            "waveform": { "type": "sine", "amplitude": 2.0, "frequency": 1.0, "offset": 1.0 },
+        }));
+        let mut noise = Noise::new(1);
+        assert!(close(sine.value(0, 0.0, &mut noise), 1.0));
+        assert!(close(sine.value(0, 0.25, &mut noise), 3.0));
+        // a quarter of a period behind channel 0
+        assert!(close(sine.value(4, 0.0, &mut noise), 3.0));
+    }
+
+    #[test]
+    fn alternates_steps_each_period() {
+        let step = daq(json!({
+            "waveform": { "type": "step", "low": -1.0, "high": 1.0, "period": 0.5 },
+        }));
+        let mut noise = Noise::new(1);
+        let values: Vec<f64> = [0.1, 0.6, 1.1]
+            .iter()
+            .map(|seconds| step.value(0, *seconds, &mut noise))
+            .collect();
+        assert_eq!(values, [-1.0, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn keeps_noise_within_its_amplitude() {
+        let noisy = daq(json!({
+            "waveform": { "type": "noise", "amplitude": 0.5, "offset": 2.0 },
+        }));
+        let mut noise = Noise::new(1);
+        let values: Vec<f64> = (0..1000).map(|_| noisy.value(0, 0.0, &mut noise)).collect();
+        assert!(values.iter().all(|value| (1.5..2.5).contains(value)));
+        assert!(values.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn wraps_timestamps_like_the_hardware() {
+        let daq = daq(json!({ "edge_interval_ms": 250 }));
+        assert_eq!(daq.edge_interval(), Some(Duration::from_millis(250)));
+        assert_eq!(daq.ticks(Duration::from_millis(1500)), 1_500_000);
+        let elapsed = Duration::from_micros(u32::MAX as u64 + 11);
+        assert_eq!(daq.ticks(elapsed), u32::MAX as u64 + 11);
+        assert_eq!(daq.timestamp(elapsed), 10);
+    }
+}
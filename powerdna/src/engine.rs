@@ -5,6 +5,7 @@ use powerdna_sys::{
     DQ_SS0IN, DQ_SS0OUT,
 };
 use std::ptr;
+use tracing::error;
 
 pub enum InterfaceType {
     Input,
@@ -54,12 +55,9 @@ impl DqEngine {
 
 impl Drop for DqEngine {
     fn drop(&mut self) {
-        match parse_err!(DqStopDQEngine(self.dqe)) {
-            Err(err) => {
-                eprintln!("DqStopDQEngine failed. Error: {:?}", err);
-            }
-            Ok(_) => {}
-        };
+        if let Err(err) = parse_err!(DqStopDQEngine(self.dqe)) {
+            error!(error = ?err, "DqStopDQEngine failed");
+        }
         unsafe {
             DqCleanUpDAQLib();
         }
@@ -0,0 +1,80 @@
+use crate::config::BoardModel;
+use serde::Serialize;
+
+/// What is fitted to an IOM, as reported by the cube itself.
+#[derive(Debug, Clone, Serialize)]
+pub struct Inventory {
+    pub ip: String,
+    /// Name assigned to the IOM (`DqCmdGetName`).
+    pub name: String,
+    pub model: String,
+    pub serial: u32,
+    /// Manufacturing and calibration dates, in the cube's own encoding.
+    pub manufactured: u32,
+    pub calibrated: u32,
+    pub layers: Vec<Layer>,
+}
+
+/// A board in one of the IOM's slots.
+#[derive(Debug, Clone, Serialize)]
+pub struct Layer {
+    pub device: u8,
+    /// Model name, e.g. `AI-201`.
+    pub model: String,
+    /// Raw model number, e.g. `0x201`.
+    pub model_id: u16,
+    pub option: u16,
+    /// Only known for the boards Daiquiri supports.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<LayerChannels>,
+    /// `STS_FW` status word.
+    pub firmware_status: u32,
+    /// Whether the layer's firmware is in operating rather than configuration mode.
+    pub operating: bool,
+    /// Capability report from `DqCmdGetCapabilities`, one entry per line.
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LayerChannels {
+    pub analog_inputs: u32,
+    pub digital_inputs: u32,
+    pub digital_outputs: u32,
+}
+
+/// Model number of the digital layer that drives the buzzer. Analogue input layers are listed by
+/// `BoardModel`.
+pub const DIO405: u16 = 0x405;
+
+/// Builds a model name like `AI-201` from the layer's model number, whose first digit gives the
+/// layer's family.
+pub fn model_name(model_id: u16) -> String {
+    let family = match model_id >> 8 {
+        0x1 => "PC",
+        0x2 => "AI",
+        0x3 => "AO",
+        0x4 => "DIO",
+        0x5 => "SL",
+        0x6 => "CT",
+        _ => return format!("0x{:03x}", model_id),
+    };
+    format!("{}-{:x}", family, model_id)
+}
+
+pub(crate) fn channels(model_id: u16) -> Option<LayerChannels> {
+    if let Some(model) = BoardModel::from_model_id(model_id) {
+        return Some(LayerChannels {
+            analog_inputs: model.spec().channels,
+            digital_inputs: 0,
+            digital_outputs: 0,
+        });
+    }
+    match model_id {
+        DIO405 => Some(LayerChannels {
+            analog_inputs: 0,
+            digital_inputs: 12,
+            digital_outputs: 12,
+        }),
+        _ => None,
+    }
+}
@@ -1,69 +1,47 @@
-use crate::config::{BoardConfig, OutputConfig};
-use crate::daq::Daq;
-use crate::stream::Sampler;
-use powerdna_sys::DQ_AI201_GAIN_10_100;
-use powerdna_sys::DQ_AI201_GAIN_1_100;
-use powerdna_sys::DQ_AI201_GAIN_2_100;
-use powerdna_sys::DQ_AI201_GAIN_5_100;
+use crate::config::StreamConfig;
+use crate::daq::DaqBackend;
+use crate::session::{Session, StartOptions, StopReason};
+use crate::stats::StreamStats;
+use crate::stream::{Destination, Sampler};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::SystemTime;
 use thiserror::Error;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use tracing::{error, info, info_span, Instrument, Span};
 
 #[macro_use]
 mod results;
 
+pub use crate::results::PowerDnaError;
+
 #[macro_use]
 extern crate num_derive;
 
 mod boards;
+mod clock;
 pub mod config;
 pub mod daq;
 pub mod engine;
+pub mod inventory;
+mod queue;
+mod recording;
+pub mod session;
+pub mod stats;
 mod stream;
 
-use serde::de::Visitor;
-use serde::{de, Deserialize, Deserializer};
-use std::fmt;
+use serde::{Deserialize, Serialize};
 use std::prelude::v1::Result::Ok;
 
-#[derive(Debug, ToPrimitive)]
-#[repr(u32)]
-pub enum Gain {
-    One = DQ_AI201_GAIN_1_100,
-    Two = DQ_AI201_GAIN_2_100,
-    Five = DQ_AI201_GAIN_5_100,
-    Ten = DQ_AI201_GAIN_10_100,
-}
-
-struct GainVisitor;
+/// Input gain as written in the stream config, e.g. `10`. Which gains are available depends on the
+/// board's `type`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Gain(u32);
 
-impl<'de> Visitor<'de> for GainVisitor {
-    type Value = Gain;
-
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("the integers 1, 2, 5 or 10")
-    }
-
-    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
-    where
-        E: de::Error,
-    {
-        match value {
-            1 => Ok(Gain::One),
-            2 => Ok(Gain::Two),
-            5 => Ok(Gain::Five),
-            10 => Ok(Gain::Ten),
-            _ => Err(E::custom(format!("expected 1, 2, 5 or 10: {}", value))),
-        }
-    }
-}
-
-impl<'de> Deserialize<'de> for Gain {
-    fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        deserializer.deserialize_i32(GainVisitor)
+impl Gain {
+    pub fn factor(&self) -> u32 {
+        self.0
     }
 }
 
@@ -71,7 +49,7 @@ impl<'de> Deserialize<'de> for Gain {
 pub enum DaqError {
     #[error("Failed to allocate inbound data buffer.")]
     BufferError,
-    #[error("Internal error.")]
+    #[error("PowerDNA error: {source}")]
     PowerDnaError {
         #[from]
         source: results::PowerDnaError,
@@ -84,69 +62,283 @@ pub enum DaqError {
     GainConfigError,
     #[error("Error decoding timestamps.")]
     TimestampDecodeError,
+    #[error("Failed to start recording: {0}")]
+    RecordingError(#[source] std::io::Error),
+    #[error("Configured boards don't match the IOM: {}", config::join_problems(.problems))]
+    HardwareMismatchError {
+        problems: Vec<config::ConfigProblem>,
+    },
+}
+
+/// A frame of samples merged from all of a stream's input boards. `data` is laid out scan by scan,
+/// with one value per channel in the order the channels are configured.
+#[derive(Debug)]
+pub struct SensorData {
+    pub stream: String,
+    /// Id of the session the frame was sampled in.
+    pub session: String,
+    /// Counts the stream's frames from 0, without resetting between sessions, so that frames lost
+    /// on the way to a consumer show up as a jump.
+    pub sequence: u64,
+    pub channels: usize,
+    pub data: Vec<f64>,
+    /// Raw 32-bit hardware timestamp of each scan.
+    pub timestamps: Vec<u32>,
+    /// `timestamps` unwrapped into a counter that keeps increasing for as long as the stream runs.
+    pub ticks: Vec<u64>,
+    /// Wall-clock time of each scan in nanoseconds since the UNIX epoch, derived from `ticks`.
+    pub unix_ns: Vec<u64>,
+}
+
+impl SensorData {
+    pub fn samples(&self) -> usize {
+        self.timestamps.len()
+    }
+}
+
+/// Describes where a channel's samples in `SensorData` come from.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelMetadata {
+    pub name: String,
+    pub device: u8,
+    pub channel: u8,
+    pub gain: u32,
+    pub unit: String,
+}
+
+#[derive(Debug)]
+pub enum StreamEvent {
+    Buzzer {
+        timestamp: u32,
+        ticks: u64,
+        unix_ns: u64,
+    },
+    /// Sent on start by streams with `metadata` enabled.
+    Metadata {
+        freq: f32,
+        channels: Vec<ChannelMetadata>,
+    },
+    SessionStarted {
+        id: String,
+        label: Option<String>,
+        metadata: Option<serde_json::Value>,
+        unix_ns: u64,
+    },
+    SessionStopped {
+        id: String,
+        unix_ns: u64,
+        /// Frames produced during the session.
+        frames: u64,
+        reason: StopReason,
+    },
+    /// Scans are missing between two that were received. `sequence` is that of the frame holding
+    /// the scan after the gap.
+    DataGap {
+        sequence: u64,
+        from_ticks: u64,
+        from_unix_ns: u64,
+        to_ticks: u64,
+        to_unix_ns: u64,
+        missing_scans: u64,
+    },
+}
+
+/// A stream event on its way to the publisher, labelled like `SensorData`.
+#[derive(Debug)]
+pub struct EventData {
+    pub stream: String,
+    /// Id of the session the event happened in.
+    pub session: String,
+    pub event: StreamEvent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamState {
+    Stopped,
+    Running,
+    /// Stopped without being asked to, or its IOM isn't responding.
+    Faulted,
+}
+
+/// Why a stream is faulted.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    pub reason: String,
+    pub since: SystemTime,
+    /// Options of the session that was running when the stream faulted, if any. The stream is
+    /// restarted with them when it recovers, with a timed acquisition's limit reduced to the
+    /// scans it had left.
+    pub resume: Option<StartOptions>,
+}
+
+/// Snapshot of a stream's acquisition state.
+pub struct StreamStatus {
+    pub state: StreamState,
+    pub running: bool,
+    pub fault: Option<Fault>,
+    pub session: Option<Session>,
+    /// Sampling rate accepted by the first input board, which may differ from the requested one.
+    pub actual_freq: Option<f32>,
+    pub started: Option<SystemTime>,
+    pub frames: u64,
+    pub packets_lost: u64,
+    /// Frames waiting to be merged by the muxer thread.
+    pub muxer_queue: u64,
+    /// Frames waiting to be published.
+    pub publisher_queue: u64,
+    pub frames_dropped: u64,
+    pub events_dropped: u64,
+    pub data_gaps: u64,
 }
 
 pub struct SignalManager {
     name: String,
-    freq: u32,
-    frame_size: u32,
-    boards: Vec<BoardConfig>,
-    outputs: Vec<OutputConfig>,
+    config: StreamConfig,
     sampler: Option<Sampler>,
-    out: UnboundedSender<(String, Vec<f64>, Vec<u32>)>,
-    buzzer_out: UnboundedSender<(String, u32)>,
-    daq: Arc<Daq>,
+    session: Option<Session>,
+    fault: Option<Fault>,
+    stats: Arc<StreamStats>,
+    out: Sender<SensorData>,
+    events: Sender<EventData>,
+    finished: UnboundedSender<String>,
+    backend: DaqBackend,
+    span: Span,
 }
 
 impl SignalManager {
     pub fn new(
         name: String,
-        freq: u32,
-        frame_size: u32,
-        boards: Vec<BoardConfig>,
-        outputs: Vec<OutputConfig>,
-        daq: Arc<Daq>,
-        out: UnboundedSender<(String, Vec<f64>, Vec<u32>)>,
-        buzzer_out: UnboundedSender<(String, u32)>,
+        config: StreamConfig,
+        backend: DaqBackend,
+        out: Sender<SensorData>,
+        events: Sender<EventData>,
+        finished: UnboundedSender<String>,
         sampler: Option<Sampler>,
     ) -> Self {
         SignalManager {
+            span: info_span!("stream", stream = %name, iom = %backend.address()),
             name,
-            freq,
-            frame_size,
-            boards,
-            outputs,
-            daq,
+            stats: Arc::new(StreamStats::new(&config)),
+            config,
+            backend,
             out,
-            buzzer_out,
+            events,
+            finished,
             sampler,
+            session: None,
+            fault: None,
         }
     }
 
-    pub fn start(&mut self) -> Result<(), DaqError> {
-        match self.sampler {
-            Some(_) => Err(DaqError::StreamStateError),
-            None => {
-                let sampler = match Sampler::new(
-                    self.daq.clone(),
-                    self.freq,
-                    self.frame_size,
-                    &self.boards,
-                    &self.outputs,
-                    self.out.clone(),
-                    self.buzzer_out.clone(),
-                    self.name.clone(),
-                ) {
-                    Ok(sampler) => sampler,
-                    Err(err) => {
-                        println!("{:?}", err);
-                        return Err(err);
-                    }
-                };
-                self.sampler = Some(sampler);
-                Ok(())
+    pub fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+
+    pub fn backend(&self) -> &DaqBackend {
+        &self.backend
+    }
+
+    /// The running session, if any.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    pub fn status(&self) -> StreamStatus {
+        let state = match (&self.sampler, &self.fault) {
+            (Some(_), _) => StreamState::Running,
+            (None, Some(_)) => StreamState::Faulted,
+            (None, None) => StreamState::Stopped,
+        };
+        StreamStatus {
+            state,
+            running: self.sampler.is_some(),
+            fault: self.fault.clone(),
+            session: self.session.clone(),
+            actual_freq: self
+                .sampler
+                .as_ref()
+                .and_then(|sampler| sampler.actual_freq()),
+            started: self.sampler.as_ref().map(|sampler| sampler.started()),
+            frames: self.stats.frames.load(Ordering::Relaxed),
+            packets_lost: self.stats.packets_lost(),
+            muxer_queue: self.stats.queued_frames.load(Ordering::Relaxed),
+            publisher_queue: self.config.queue.depth.saturating_sub(self.out.capacity()) as u64,
+            frames_dropped: self.stats.frames_dropped.load(Ordering::Relaxed),
+            events_dropped: self.stats.events_dropped.load(Ordering::Relaxed),
+            data_gaps: self.stats.data_gaps.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Starts a new session. Its id is returned by `status`, and is carried by every frame.
+    pub async fn start(&mut self, options: StartOptions) -> Result<(), DaqError> {
+        let span = self.span.clone();
+        async {
+            if self.sampler.is_some() {
+                return Err(DaqError::StreamStateError);
+            }
+            let session = Session::new(options, self.stats.frames.load(Ordering::Relaxed));
+            let destination = Destination {
+                topic: self.name.clone(),
+                session: session.id.clone(),
+                out: self.out.clone(),
+                events: self.events.clone(),
+                finished: self.finished.clone(),
+            };
+            let mut sampler = match Sampler::new(
+                &self.backend,
+                &self.config,
+                destination,
+                &session.options,
+                Arc::clone(&self.stats),
+                self.span.clone(),
+            ) {
+                Ok(sampler) => sampler,
+                Err(err) => {
+                    error!(error = %err, "Failed to start stream");
+                    return Err(err);
+                }
+            };
+            // announced before the boards are started, so that it precedes all of their events
+            self.publish(
+                &session.id,
+                StreamEvent::SessionStarted {
+                    id: session.id.clone(),
+                    label: session.options.label.clone(),
+                    metadata: session.options.metadata.clone(),
+                    unix_ns: session::unix_ns(session.started),
+                },
+            )
+            .await;
+            if self.config.metadata {
+                self.publish(
+                    &session.id,
+                    StreamEvent::Metadata {
+                        freq: sampler.actual_freq().unwrap_or(self.config.freq as f32),
+                        channels: self.config.channel_metadata(),
+                    },
+                )
+                .await;
+            }
+            let id = session.id.clone();
+            self.session = Some(session);
+            if let Err(err) = sampler.run() {
+                error!(error = %err, "Failed to start stream");
+                drop(sampler);
+                self.end_session(StopReason::Faulted).await;
+                return Err(err);
             }
+            info!(session = %id, "Stream started");
+            self.sampler = Some(sampler);
+            self.fault = None;
+            Ok(())
         }
+        .instrument(span)
+        .await
     }
 
     pub async fn trigger(&mut self) -> Result<(), DaqError> {
@@ -159,13 +351,239 @@ impl SignalManager {
         }
     }
 
-    pub fn stop(&mut self) -> Result<(), DaqError> {
-        match self.sampler {
-            Some(_) => {
-                self.sampler = None;
-                Ok(())
+    /// Stopping a faulted stream clears the fault, so that it isn't restarted when it recovers.
+    pub async fn stop(&mut self) -> Result<(), DaqError> {
+        let span = self.span.clone();
+        async {
+            match (&self.sampler, &self.fault) {
+                (Some(_), _) => {
+                    self.sampler = None;
+                    self.end_session(StopReason::Requested).await;
+                    info!("Stream stopped");
+                    Ok(())
+                }
+                (None, Some(_)) => {
+                    self.fault = None;
+                    info!("Fault cleared");
+                    Ok(())
+                }
+                (None, None) => Err(DaqError::StreamStateError),
             }
-            None => Err(DaqError::StreamStateError),
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Faults the stream if its sampler's threads have exited by themselves. A faulted sampler is
+    /// dropped to release the hardware, as is one whose timed acquisition has completed. Idle
+    /// streams are checked by pinging their `idle_backend` instead, since that blocks. Returns
+    /// whether the stream is faulted.
+    pub async fn check_health(&mut self) -> bool {
+        if self.fault.is_some() {
+            return true;
+        }
+        let span = self.span.clone();
+        async {
+            let reason = match &self.sampler {
+                Some(sampler) if sampler.completed() => {
+                    self.sampler = None;
+                    self.end_session(StopReason::Completed).await;
+                    info!("Stream stopped");
+                    return false;
+                }
+                Some(sampler) if sampler.failed() => {
+                    String::from("a board thread stopped unexpectedly")
+                }
+                Some(_) | None => return false,
+            };
+            self.fault(reason).await;
+            true
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// The backend of a stream that is neither running nor faulted, for its IOM to be pinged.
+    pub fn idle_backend(&self) -> Option<DaqBackend> {
+        match (&self.sampler, &self.fault) {
+            (None, None) => Some(self.backend.clone()),
+            _ => None,
+        }
+    }
+
+    /// Faults a stream whose IOM didn't answer a ping, unless it has been started or reconnected
+    /// since `backend` was taken from it. Returns whether the stream is faulted.
+    pub async fn fault_unresponsive(&mut self, backend: &DaqBackend, err: PowerDnaError) -> bool {
+        match self.idle_backend() {
+            Some(idle) if Arc::ptr_eq(&idle, backend) => {
+                let reason = format!("IOM isn't responding: {}", err);
+                let span = self.span.clone();
+                self.fault(reason).instrument(span).await;
+                true
+            }
+            _ => self.fault.is_some(),
+        }
+    }
+
+    /// Drops the sampler and ends the session, remembering how to resume it on reconnection.
+    async fn fault(&mut self, reason: String) {
+        error!(reason = %reason, "Stream faulted");
+        let remaining = self.sampler.as_ref().and_then(Sampler::remaining);
+        self.fault = Some(Fault {
+            reason,
+            since: SystemTime::now(),
+            resume: self
+                .session
+                .as_ref()
+                .map(|session| session.options.resume(remaining)),
+        });
+        self.sampler = None;
+        self.end_session(StopReason::Faulted).await;
+    }
+
+    /// Publishes a `SessionStopped` event for the running session. The sampler must have been
+    /// dropped first, so that its frame count is final.
+    async fn end_session(&mut self, reason: StopReason) {
+        if let Some(session) = self.session.take() {
+            let frames = self.stats.frames.load(Ordering::Relaxed) - session.first_frame;
+            self.publish(
+                &session.id,
+                StreamEvent::SessionStopped {
+                    id: session.id.clone(),
+                    unix_ns: session::unix_ns(SystemTime::now()),
+                    frames,
+                    reason,
+                },
+            )
+            .await;
+        }
+    }
+
+    /// Publishes a stream event, waiting for room if the stream's overflow policy is `Block`.
+    async fn publish(&self, session: &str, event: StreamEvent) {
+        let event = EventData {
+            stream: self.name.clone(),
+            session: session.to_string(),
+            event,
+        };
+        if let Err(err) = queue::send(
+            &self.events,
+            event,
+            self.config.queue.overflow,
+            &self.stats.events_dropped,
+        )
+        .await
+        {
+            error!(error = %err, "Failed to send stream event");
+        }
+    }
+
+    /// Recovers a faulted stream over a newly opened backend, restarting it if it was running when
+    /// it faulted. The old connection is closed once it has been replaced.
+    pub async fn reconnect(&mut self, backend: DaqBackend) -> Result<(), DaqError> {
+        let fault = match self.fault.take() {
+            Some(fault) => fault,
+            None => return Err(DaqError::StreamStateError),
+        };
+        self.backend = backend;
+        self.span.in_scope(|| {
+            info!(
+                faulted_for = ?fault.since.elapsed().unwrap_or_default(),
+                "Stream reconnected"
+            )
+        });
+        if let Some(options) = fault.resume {
+            if let Err(err) = self.start(options.clone()).await {
+                self.fault = Some(Fault {
+                    reason: err.to_string(),
+                    since: SystemTime::now(),
+                    resume: Some(options),
+                });
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daq::simulated::SimulatedDaq;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{self, Receiver};
+    use tokio::time;
+
+    async fn next<T>(rx: &mut Receiver<T>) -> T {
+        time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("timed out")
+            .expect("queue closed")
+    }
+
+    #[tokio::test]
+    async fn streams_simulated_frames_from_start_to_stop() {
+        let config: StreamConfig = serde_json::from_value(json!({
+            "ip": "192.168.100.2",
+            "freq": 1000,
+            "frame_size": 10,
+            "boards": [{
+                "device": 0,
+                "channels": [{ "id": 0, "gain": 1 }, { "id": 1, "gain": 1, "scale": 2.0 }],
+            }],
+            "outputs": [{ "device": 3 }],
+            "simulation": {
+                "waveform": { "type": "step", "low": 1.0, "high": 1.0, "period": 1.0 },
+            },
+        }))
+        .unwrap();
+        let backend = Arc::new(SimulatedDaq::new(config.simulation.clone().unwrap()));
+        let (out, mut frames) = mpsc::channel(64);
+        let (events_tx, mut events) = mpsc::channel(64);
+        let (finished, _finished) = mpsc::unbounded_channel();
+        let mut manager = SignalManager::new(
+            String::from("rig"),
+            config,
+            backend,
+            out,
+            events_tx,
+            finished,
+            None,
+        );
+
+        manager.start(StartOptions::default()).await.unwrap();
+        assert_eq!(manager.status().state, StreamState::Running);
+        let session = manager.session().unwrap().id.clone();
+        match next(&mut events).await.event {
+            StreamEvent::SessionStarted { id, .. } => assert_eq!(id, session),
+            event => panic!("unexpected event {:?}", event),
+        }
+        for sequence in 0..3 {
+            let frame = next(&mut frames).await;
+            assert_eq!(frame.session, session);
+            assert_eq!(frame.sequence, sequence);
+            assert_eq!(frame.samples(), 10);
+            assert_eq!(frame.data, [1.0, 2.0].repeat(10));
+        }
+
+        manager.trigger().await.unwrap();
+        match next(&mut events).await.event {
+            StreamEvent::Buzzer { .. } => (),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        manager.stop().await.unwrap();
+        assert_eq!(manager.status().state, StreamState::Stopped);
+        match next(&mut events).await.event {
+            StreamEvent::SessionStopped {
+                id, frames, reason, ..
+            } => {
+                assert_eq!(id, session);
+                assert!(frames >= 3);
+                assert_eq!(reason, StopReason::Requested);
+            }
+            event => panic!("unexpected event {:?}", event),
         }
     }
 }
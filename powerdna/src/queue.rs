@@ -0,0 +1,220 @@
+use crate::config::OverflowPolicy;
+use crate::stats::StreamStats;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Longest a blocked sender waits for room before checking whether it should give up.
+const STOP_CHECK: Duration = Duration::from_millis(50);
+
+/// Bounded queue of frames between a board thread and the muxer thread. It never drops frames,
+/// since the muxer combines one frame from each board at a time: a frame dropped by one board's
+/// queue would misalign every later frame with the other boards'. The stream's overflow policy is
+/// applied after the frames have been merged.
+pub(crate) fn bounded<T>(
+    depth: usize,
+    stop: Arc<AtomicBool>,
+    stats: Arc<StreamStats>,
+) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    let (tx, rx) = mpsc::channel(depth);
+    (
+        BoundedSender {
+            tx,
+            stop,
+            stats: Arc::clone(&stats),
+        },
+        BoundedReceiver { rx, stats },
+    )
+}
+
+pub(crate) struct BoundedSender<T> {
+    tx: Sender<T>,
+    /// Blocked sends give up once this is set, so that stopping the stream can't deadlock.
+    stop: Arc<AtomicBool>,
+    stats: Arc<StreamStats>,
+}
+
+impl<T> BoundedSender<T> {
+    /// Fails once the receiving end has gone away, or while blocked once the stream is stopping.
+    pub(crate) fn send(&self, value: T) -> Result<(), SendError<T>> {
+        // counted up front so that the muxer never sees a negative depth
+        self.stats.queued_frames.fetch_add(1, Ordering::Relaxed);
+        let result = match self.tx.try_send(value) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Closed(value)) => Err(SendError(value)),
+            Err(TrySendError::Full(value)) => block(&self.tx, value, Some(&self.stop)),
+        };
+        if result.is_err() {
+            self.stats.queued_frames.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+pub(crate) struct BoundedReceiver<T> {
+    rx: Receiver<T>,
+    stats: Arc<StreamStats>,
+}
+
+impl<T> BoundedReceiver<T> {
+    /// Waits for the next frame. Returns `None` once every sender has gone away.
+    pub(crate) fn recv(&mut self) -> Option<T> {
+        let value = self.rx.blocking_recv()?;
+        self.stats.queued_frames.fetch_sub(1, Ordering::Relaxed);
+        Some(value)
+    }
+}
+
+/// Pushes onto one of the publisher's queues from a board or muxer thread, applying the overflow
+/// policy if it is full. With `Block`, the thread waits until there is room, or until `stop` is
+/// set. Code running on the runtime uses `send` instead.
+pub(crate) fn publish<T>(
+    tx: &Sender<T>,
+    value: T,
+    policy: OverflowPolicy,
+    dropped: &AtomicU64,
+    stop: Option<&AtomicBool>,
+) -> Result<(), SendError<T>> {
+    match tx.try_send(value) {
+        Ok(_) => Ok(()),
+        Err(TrySendError::Closed(value)) => Err(SendError(value)),
+        Err(TrySendError::Full(value)) => match policy {
+            OverflowPolicy::Block => block(tx, value, stop),
+            OverflowPolicy::DropNewest => {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        },
+    }
+}
+
+/// Pushes onto one of the publisher's queues from the runtime, applying the overflow policy if it
+/// is full. With `Block`, this waits for room, so values sent one after the other stay in order.
+pub(crate) async fn send<T>(
+    tx: &Sender<T>,
+    value: T,
+    policy: OverflowPolicy,
+    dropped: &AtomicU64,
+) -> Result<(), SendError<T>> {
+    match policy {
+        OverflowPolicy::Block => tx.send(value).await,
+        OverflowPolicy::DropNewest => match tx.try_send(value) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Closed(value)) => Err(SendError(value)),
+            Err(TrySendError::Full(_)) => {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        },
+    }
+}
+
+/// Parks the thread until the receiver makes room, checking `stop` at least every `STOP_CHECK`.
+fn block<T>(tx: &Sender<T>, value: T, stop: Option<&AtomicBool>) -> Result<(), SendError<T>> {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut reserve = pin!(tx.reserve());
+    loop {
+        match reserve.as_mut().poll(&mut context) {
+            Poll::Ready(Ok(permit)) => {
+                permit.send(value);
+                return Ok(());
+            }
+            Poll::Ready(Err(_)) => return Err(SendError(value)),
+            Poll::Pending => (),
+        }
+        if stop.is_some_and(|stop| stop.load(Ordering::SeqCst)) {
+            return Err(SendError(value));
+        }
+        thread::park_timeout(STOP_CHECK);
+    }
+}
+
+/// Wakes a thread that is blocked waiting for room in a queue.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_blocked_send_gives_up_once_stopped() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(StreamStats::default());
+        let (tx, _rx) = bounded(1, Arc::clone(&stop), Arc::clone(&stats));
+        tx.send(1).unwrap();
+        let blocked = thread::spawn(move || tx.send(2));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!blocked.is_finished());
+        stop.store(true, Ordering::SeqCst);
+        assert!(blocked.join().unwrap().is_err());
+        assert_eq!(stats.queued_frames.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drops_the_newest_value_when_full() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let dropped = AtomicU64::new(0);
+        publish(&tx, 1, OverflowPolicy::DropNewest, &dropped, None).unwrap();
+        publish(&tx, 2, OverflowPolicy::DropNewest, &dropped, None).unwrap();
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn a_blocked_publish_waits_for_room() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let dropped = AtomicU64::new(0);
+        publish(&tx, 1, OverflowPolicy::Block, &dropped, None).unwrap();
+        let receiver = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            (rx.blocking_recv(), rx.blocking_recv())
+        });
+        publish(&tx, 2, OverflowPolicy::Block, &dropped, None).unwrap();
+        assert_eq!(receiver.join().unwrap(), (Some(1), Some(2)));
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn sends_from_the_runtime_in_order() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let dropped = AtomicU64::new(0);
+        let receiver = tokio::spawn(async move {
+            let mut values = Vec::new();
+            while let Some(value) = rx.recv().await {
+                values.push(value);
+            }
+            values
+        });
+        for value in 0..10 {
+            send(&tx, value, OverflowPolicy::Block, &dropped)
+                .await
+                .unwrap();
+        }
+        drop(tx);
+        assert_eq!(receiver.await.unwrap(), (0..10).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn a_blocked_publish_gives_up_once_stopped() {
+        let (tx, _rx) = mpsc::channel(1);
+        let dropped = AtomicU64::new(0);
+        let stop = AtomicBool::new(false);
+        publish(&tx, 1, OverflowPolicy::Block, &dropped, Some(&stop)).unwrap();
+        stop.store(true, Ordering::SeqCst);
+        assert!(publish(&tx, 2, OverflowPolicy::Block, &dropped, Some(&stop)).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use crate::config::{RecordingConfig, RecordingFormat};
+use crate::{ChannelMetadata, DaqError, SensorData, StreamEvent};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Layout of a recording, written at the top of every file so that each one can be read on its
+/// own.
+#[derive(Serialize)]
+struct Header<'a> {
+    stream: &'a str,
+    session: &'a str,
+    freq: f32,
+    started_unix_ns: u64,
+    channels: &'a [ChannelMetadata],
+}
+
+/// JSON Lines records, tagged the same way as the live feed.
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Record<'a> {
+    StreamHeader(&'a Header<'a>),
+    SensorFrame {
+        sequence: u64,
+        samples: usize,
+        timestamps: &'a [u32],
+        ticks: &'a [u64],
+        unix_ns: &'a [u64],
+        frame: &'a [f64],
+    },
+    BuzzerEvent {
+        timestamp: u32,
+        ticks: u64,
+        unix_ns: u64,
+    },
+    DataGap {
+        sequence: u64,
+        from_ticks: u64,
+        from_unix_ns: u64,
+        to_ticks: u64,
+        to_unix_ns: u64,
+        missing_scans: u64,
+    },
+}
+
+struct RecordingFile {
+    writer: BufWriter<File>,
+    bytes: u64,
+    opened: Instant,
+}
+
+/// Writes a running stream's frames and buzzer events to rotating files. A new set of files is
+/// started each time the stream starts.
+pub(crate) struct Recorder {
+    stream: String,
+    session: String,
+    config: RecordingConfig,
+    freq: f32,
+    started_unix_ns: u64,
+    channels: Vec<ChannelMetadata>,
+    file: Mutex<Option<RecordingFile>>,
+    index: AtomicU32,
+}
+
+impl Recorder {
+    pub(crate) fn new(
+        stream: &str,
+        session: &str,
+        config: &RecordingConfig,
+        freq: f32,
+        started: SystemTime,
+        channels: Vec<ChannelMetadata>,
+    ) -> Result<Self, DaqError> {
+        fs::create_dir_all(&config.directory).map_err(DaqError::RecordingError)?;
+        let recorder = Recorder {
+            stream: stream.to_string(),
+            session: session.to_string(),
+            config: config.clone(),
+            freq,
+            started_unix_ns: started
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_nanos() as u64)
+                .unwrap_or(0),
+            channels,
+            file: Mutex::new(None),
+            index: AtomicU32::new(0),
+        };
+        let file = recorder.open().map_err(DaqError::RecordingError)?;
+        *recorder.file.lock().unwrap_or_else(|err| err.into_inner()) = Some(file);
+        Ok(recorder)
+    }
+
+    fn path(&self, index: u32) -> PathBuf {
+        let extension = match self.config.format {
+            RecordingFormat::Csv => "csv",
+            RecordingFormat::Jsonl => "jsonl",
+        };
+        PathBuf::from(&self.config.directory).join(format!(
+            "{}-{}-{}-{:04}.{}",
+            self.stream,
+            self.started_unix_ns / 1_000_000_000,
+            self.session,
+            index,
+            extension
+        ))
+    }
+
+    fn open(&self) -> Result<RecordingFile, io::Error> {
+        let index = self.index.fetch_add(1, Ordering::Relaxed);
+        // never truncates an earlier recording
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.path(index))?;
+
+        let mut file = RecordingFile {
+            writer: BufWriter::new(file),
+            bytes: 0,
+            opened: Instant::now(),
+        };
+        let header = Header {
+            stream: &self.stream,
+            session: &self.session,
+            freq: self.freq,
+            started_unix_ns: self.started_unix_ns,
+            channels: &self.channels,
+        };
+        let text = match self.config.format {
+            RecordingFormat::Csv => {
+                let mut text = format!(
+                    "# stream: {}\n# session: {}\n# freq: {}\n# started_unix_ns: {}\n",
+                    header.stream, header.session, header.freq, header.started_unix_ns
+                );
+                let mut columns = vec![
+                    String::from("type"),
+                    String::from("timestamp"),
+                    String::from("ticks"),
+                    String::from("unix_ns"),
+                ];
+                for channel in &self.channels {
+                    text.push_str(&format!(
+                        "# channel: {} (device {}, channel {}, gain {}, unit {})\n",
+                        channel.name, channel.device, channel.channel, channel.gain, channel.unit
+                    ));
+                    columns.push(format!("{} [{}]", channel.name, channel.unit));
+                }
+                text + &csv_row(&columns)
+            }
+            RecordingFormat::Jsonl => json_line(&Record::StreamHeader(&header))?,
+        };
+        file.write(&text)?;
+        Ok(file)
+    }
+
+    pub(crate) fn record_frame(&self, frame: &SensorData) {
+        let text = match self.config.format {
+            RecordingFormat::Csv => (0..frame.samples())
+                .map(|scan| {
+                    let mut row = vec![
+                        String::from("sample"),
+                        frame.timestamps[scan].to_string(),
+                        frame.ticks[scan].to_string(),
+                        frame.unix_ns[scan].to_string(),
+                    ];
+                    let values = &frame.data[scan * frame.channels..(scan + 1) * frame.channels];
+                    row.extend(values.iter().map(|value| value.to_string()));
+                    csv_row(&row)
+                })
+                .collect(),
+            RecordingFormat::Jsonl => match json_line(&Record::SensorFrame {
+                sequence: frame.sequence,
+                samples: frame.samples(),
+                timestamps: &frame.timestamps,
+                ticks: &frame.ticks,
+                unix_ns: &frame.unix_ns,
+                frame: &frame.data,
+            }) {
+                Ok(text) => text,
+                Err(err) => {
+                    error!(error = %err, "Failed to serialise recorded frame");
+                    return;
+                }
+            },
+        };
+        self.write(&text);
+    }
+
+    /// Buzzer events are recorded in both formats, and data gaps only in `jsonl` files since they
+    /// are already apparent from the timestamps of a `csv` file.
+    pub(crate) fn record_event(&self, event: &StreamEvent) {
+        let (timestamp, ticks, unix_ns) = match event {
+            StreamEvent::Buzzer {
+                timestamp,
+                ticks,
+                unix_ns,
+            } => (*timestamp, *ticks, *unix_ns),
+            StreamEvent::DataGap {
+                sequence,
+                from_ticks,
+                from_unix_ns,
+                to_ticks,
+                to_unix_ns,
+                missing_scans,
+            } => {
+                if self.config.format == RecordingFormat::Jsonl {
+                    self.write_record(&Record::DataGap {
+                        sequence: *sequence,
+                        from_ticks: *from_ticks,
+                        from_unix_ns: *from_unix_ns,
+                        to_ticks: *to_ticks,
+                        to_unix_ns: *to_unix_ns,
+                        missing_scans: *missing_scans,
+                    });
+                }
+                return;
+            }
+            _ => return,
+        };
+        let text = match self.config.format {
+            RecordingFormat::Csv => {
+                let mut row = vec![
+                    String::from("buzzer"),
+                    timestamp.to_string(),
+                    ticks.to_string(),
+                    unix_ns.to_string(),
+                ];
+                row.extend(self.channels.iter().map(|_| String::new()));
+                csv_row(&row)
+            }
+            RecordingFormat::Jsonl => match json_line(&Record::BuzzerEvent {
+                timestamp,
+                ticks,
+                unix_ns,
+            }) {
+                Ok(text) => text,
+                Err(err) => {
+                    error!(error = %err, "Failed to serialise recorded event");
+                    return;
+                }
+            },
+        };
+        self.write(&text);
+    }
+
+    fn write_record(&self, record: &Record) {
+        match json_line(record) {
+            Ok(text) => self.write(&text),
+            Err(err) => error!(error = %err, "Failed to serialise recorded event"),
+        }
+    }
+
+    /// Appends to the current file, rotating once it is full. Recording stops at the first error so
+    /// that a full disk doesn't flood the log.
+    fn write(&self, text: &str) {
+        let mut file = self.file.lock().unwrap_or_else(|err| err.into_inner());
+        let current = match file.as_mut() {
+            Some(current) => current,
+            None => return,
+        };
+        if let Err(err) = current.write(text) {
+            error!(
+                stream = %self.stream,
+                error = %err,
+                "Failed to write recording, recording stopped"
+            );
+            *file = None;
+            return;
+        }
+
+        let too_big = matches!(self.config.max_file_bytes, Some(max) if current.bytes >= max);
+        let too_old = matches!(
+            self.config.max_file_secs,
+            Some(max) if current.opened.elapsed() >= Duration::from_secs(max)
+        );
+        if too_big || too_old {
+            let _ = current.writer.flush();
+            *file = match self.open() {
+                Ok(next) => Some(next),
+                Err(err) => {
+                    error!(
+                        stream = %self.stream,
+                        error = %err,
+                        "Failed to rotate recording, recording stopped"
+                    );
+                    None
+                }
+            };
+        }
+    }
+}
+
+impl RecordingFile {
+    fn write(&mut self, text: &str) -> Result<(), io::Error> {
+        self.writer.write_all(text.as_bytes())?;
+        self.bytes += text.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(file) = self
+            .file
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_mut()
+        {
+            if let Err(err) = file.writer.flush() {
+                error!(stream = %self.stream, error = %err, "Failed to flush recording");
+            }
+        }
+    }
+}
+
+fn csv_row(fields: &[String]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| match field.contains([',', '"', '\n']) {
+            true => format!("\"{}\"", field.replace('"', "\"\"")),
+            false => field.clone(),
+        })
+        .collect();
+    fields.join(",") + "\n"
+}
+
+fn json_line(record: &Record) -> Result<String, io::Error> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str) -> RecordingConfig {
+        let directory =
+            std::env::temp_dir().join(format!("daiquiri-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        RecordingConfig {
+            directory: directory.to_string_lossy().into_owned(),
+            format: RecordingFormat::Jsonl,
+            max_file_bytes: None,
+            max_file_secs: None,
+        }
+    }
+
+    fn recorder(config: &RecordingConfig, session: &str) -> Result<Recorder, DaqError> {
+        let started = UNIX_EPOCH + Duration::from_secs(1_000);
+        Recorder::new("s", session, config, 1000.0, started, vec![])
+    }
+
+    #[test]
+    fn sessions_started_in_the_same_second_get_files_of_their_own() {
+        let config = config("sessions");
+        let first = recorder(&config, "a").unwrap();
+        let second = recorder(&config, "b").unwrap();
+        assert_ne!(first.path(0), second.path(0));
+        assert!(first.path(0).exists() && second.path(0).exists());
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn never_truncates_an_existing_file() {
+        let config = config("existing");
+        let path = recorder(&config, "a").unwrap().path(0);
+        assert!(recorder(&config, "a").is_err());
+        let contents = fs::read_to_string(path).unwrap();
+        assert!(contents.contains("StreamHeader"));
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+}
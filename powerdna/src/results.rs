@@ -1,7 +1,9 @@
+use powerdna_sys::DqTranslateError;
 use std::error::Error;
+use std::ffi::CStr;
 use std::fmt::Display;
 
-#[derive(Debug, FromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
 #[repr(i32)]
 pub enum PowerDnaError {
     IllegalEntry = powerdna_sys::DQ_ILLEGAL_ENTRY,
@@ -40,9 +42,26 @@ pub enum PowerDnaError {
     Unknown = 0,
 }
 
+impl PowerDnaError {
+    pub fn code(&self) -> i32 {
+        *self as i32
+    }
+
+    /// Error text from the PowerDNA library (`DqTranslateError`).
+    pub fn translate(&self) -> String {
+        let text = unsafe { DqTranslateError(self.code()) };
+        if text.is_null() {
+            return String::from("Unknown error");
+        }
+        unsafe { CStr::from_ptr(text) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
 impl Display for PowerDnaError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        write!(f, "{}", *self)
+        write!(f, "{:?} ({})", self, self.translate())
     }
 }
 
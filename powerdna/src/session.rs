@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Details given in the body of a start request, all optional.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StartOptions {
+    /// Free text naming the run, e.g. the test being carried out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Arbitrary JSON passed through to the `SessionStarted` event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Stop by itself once this many seconds of data have been acquired.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<f64>,
+    /// Stop by itself once this many scans (samples per channel) have been acquired.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<u64>,
+}
+
+impl StartOptions {
+    /// Checks that at most one limit is given, and that it is positive.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.duration_secs, self.samples) {
+            (Some(_), Some(_)) => Err(String::from(
+                "only one of duration_secs and samples may be given",
+            )),
+            (Some(secs), None) if !(secs.is_finite() && secs > 0.0) => {
+                Err(String::from("duration_secs must be a positive number"))
+            }
+            (None, Some(0)) => Err(String::from("samples must be at least 1")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Options for restarting an interrupted session, which only acquires the scans it had
+    /// `remaining` if it was timed.
+    pub(crate) fn resume(&self, remaining: Option<u64>) -> StartOptions {
+        match remaining {
+            Some(scans) => StartOptions {
+                duration_secs: None,
+                samples: Some(scans),
+                ..self.clone()
+            },
+            None => self.clone(),
+        }
+    }
+
+    /// Number of scans after which the acquisition stops, if it is timed. A duration is rounded to
+    /// the nearest whole scan at the stream's actual sample rate.
+    pub(crate) fn scan_limit(&self, freq: f32) -> Option<u64> {
+        match (self.samples, self.duration_secs) {
+            (Some(samples), _) => Some(samples),
+            (None, Some(secs)) => Some(((secs * freq as f64).round() as u64).max(1)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Why a session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StopReason {
+    /// Stopped through the API.
+    Requested,
+    /// The stream faulted while running.
+    Faulted,
+    /// A timed acquisition acquired all of its scans.
+    Completed,
+}
+
+/// A single run of a stream, from when it is started to when it stops.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub options: StartOptions,
+    pub started: SystemTime,
+    /// The stream's frame count when the session started, so that frames can be counted per
+    /// session.
+    pub(crate) first_frame: u64,
+}
+
+impl Session {
+    pub(crate) fn new(options: StartOptions, first_frame: u64) -> Self {
+        Session {
+            id: Uuid::new_v4().to_string(),
+            options,
+            started: SystemTime::now(),
+            first_frame,
+        }
+    }
+}
+
+pub(crate) fn unix_ns(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(duration_secs: Option<f64>, samples: Option<u64>) -> StartOptions {
+        StartOptions {
+            duration_secs,
+            samples,
+            ..StartOptions::default()
+        }
+    }
+
+    #[test]
+    fn accepts_at_most_one_positive_limit() {
+        assert!(limits(None, None).validate().is_ok());
+        assert!(limits(Some(0.5), None).validate().is_ok());
+        assert!(limits(None, Some(1)).validate().is_ok());
+        assert!(limits(Some(1.0), Some(10)).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_empty_or_invalid_limits() {
+        for secs in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(limits(Some(secs), None).validate().is_err());
+        }
+        assert!(limits(None, Some(0)).validate().is_err());
+    }
+
+    #[test]
+    fn limits_scans_by_samples_or_duration() {
+        assert_eq!(limits(None, None).scan_limit(1000.0), None);
+        assert_eq!(limits(None, Some(250)).scan_limit(1000.0), Some(250));
+        assert_eq!(limits(Some(2.0), None).scan_limit(999.5), Some(1999));
+        assert_eq!(limits(Some(0.0001), None).scan_limit(1000.0), Some(1));
+    }
+
+    #[test]
+    fn resumes_with_the_remaining_scans() {
+        let options = StartOptions {
+            label: Some(String::from("run")),
+            ..limits(Some(2.0), None)
+        };
+        let resumed = options.resume(Some(500));
+        assert_eq!(resumed.label.as_deref(), Some("run"));
+        assert_eq!(resumed.scan_limit(1000.0), Some(500));
+        assert_eq!(options.resume(None), options);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let options = serde_json::from_value::<StartOptions>(serde_json::json!({ "sample": 10 }));
+        assert!(options.is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use crate::config::StreamConfig;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Counters shared between a stream's board threads and its `SignalManager`. They accumulate over
+/// the lifetime of the stream rather than resetting on each start.
+#[derive(Default)]
+pub struct StreamStats {
+    /// Frames produced by the muxer thread, including any dropped on the way to the publisher.
+    pub frames: AtomicU64,
+    /// Frames waiting between the board threads and the muxer thread.
+    pub queued_frames: AtomicU64,
+    /// Frames discarded because a queue was full and the stream's overflow policy allows dropping.
+    pub frames_dropped: AtomicU64,
+    /// Events discarded for the same reason.
+    pub events_dropped: AtomicU64,
+    /// Rising edges reported by the output boards.
+    pub buzzer_events: AtomicU64,
+    /// Discontinuities found between the timestamps of consecutive scans.
+    pub data_gaps: AtomicU64,
+    /// Input boards by device number.
+    pub boards: BTreeMap<u8, Arc<BoardStats>>,
+}
+
+/// Counters for a single input board.
+#[derive(Default)]
+pub struct BoardStats {
+    /// Frames handed to the muxer thread.
+    pub frames: AtomicU64,
+    /// `DQ_ePacketLost` events.
+    pub packets_lost: AtomicU64,
+    /// `DQ_eBufferError` events.
+    pub buffer_errors: AtomicU64,
+    /// `DQ_ePacketOOB` events.
+    pub packets_oob: AtomicU64,
+    /// Frames skipped because their timestamps couldn't be decoded.
+    pub timestamp_errors: AtomicU64,
+}
+
+impl StreamStats {
+    pub fn new(config: &StreamConfig) -> Self {
+        StreamStats {
+            boards: config
+                .boards
+                .iter()
+                .map(|board| (board.device, Arc::new(BoardStats::default())))
+                .collect(),
+            ..StreamStats::default()
+        }
+    }
+
+    pub(crate) fn board(&self, device: u8) -> Arc<BoardStats> {
+        self.boards.get(&device).cloned().unwrap_or_default()
+    }
+
+    /// `DQ_ePacketLost` events across all input boards.
+    pub fn packets_lost(&self) -> u64 {
+        self.boards
+            .values()
+            .map(|board| board.packets_lost.load(Ordering::Relaxed))
+            .sum()
+    }
+}
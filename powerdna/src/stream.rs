@@ -1,164 +1,487 @@
-use crate::boards::ai201::Ai201;
-use crate::boards::dio405::Dio405;
-use crate::boards::Bcb;
-use crate::config::{BoardConfig, OutputConfig};
-use crate::daq::Daq;
-use crate::DaqError;
+use crate::boards::{InputBoard, OutputBoard, RawFrame};
+use crate::clock::{StreamClock, TimestampUnwrapper};
+use crate::config::{OverflowPolicy, StreamConfig};
+use crate::daq::{DaqBackend, InputSetup, OutputSetup};
+use crate::queue::{self, BoundedReceiver};
+use crate::recording::Recorder;
+use crate::session::StartOptions;
+use crate::stats::StreamStats;
+use crate::{DaqError, EventData, SensorData, StreamEvent};
 use itertools::Itertools;
 use powerdna_sys::{pDQBCB, DqeEnable};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Receiver, RecvError};
+use std::cmp;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
-use tokio::sync::mpsc::UnboundedSender;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use tokio::time::sleep;
+use tracing::{error, info, info_span, warn, Span};
+
+/// Body of a board or muxer thread.
+type Task = Box<dyn FnOnce() + Send + Sync + 'static>;
+
+/// Where a sampler's frames and events go, and what they are labelled with.
+pub(crate) struct Destination {
+    /// Name of the stream.
+    pub topic: String,
+    pub session: String,
+    pub out: Sender<SensorData>,
+    pub events: Sender<EventData>,
+    /// Told the stream's name when a timed acquisition has acquired all of its scans.
+    pub finished: UnboundedSender<String>,
+}
 
 pub struct Sampler {
     stop: Arc<AtomicBool>,
+    completed: Arc<AtomicBool>,
+    /// Scans left to acquire in a timed acquisition, counted down by the muxer.
+    remaining: Option<Arc<AtomicU64>>,
     muxer_thread: Option<thread::JoinHandle<()>>,
-    boards: Vec<Arc<Ai201>>,
-    board_threads: Option<Vec<thread::JoinHandle<()>>>,
-    outputs: Vec<Arc<Dio405>>,
+    boards: Vec<Arc<dyn InputBoard>>,
+    board_threads: Vec<thread::JoinHandle<()>>,
+    /// Threads that `run` spawns, so that nothing is published before the session is announced.
+    pending: Vec<Task>,
+    pending_muxer: Option<Task>,
+    outputs: Vec<Arc<dyn OutputBoard>>,
+    started: SystemTime,
+    /// Whether the boards' BCBs have been enabled, and need disabling again.
+    enabled: bool,
+    span: Span,
 }
 
 impl Sampler {
-    pub fn new(
-        daq: Arc<Daq>,
-        freq: u32,
-        frame_size: u32,
-        board_configs: &Vec<BoardConfig>,
-        output_configs: &Vec<OutputConfig>,
-        out: UnboundedSender<(String, Vec<f64>, Vec<u32>)>,
-        buzzer_out: UnboundedSender<(String, u32)>,
-        topic: String,
+    /// Opens the boards and prepares their threads, which `run` then starts.
+    pub(crate) fn new(
+        backend: &DaqBackend,
+        config: &StreamConfig,
+        destination: Destination,
+        options: &StartOptions,
+        stats: Arc<StreamStats>,
+        span: Span,
     ) -> Result<Sampler, DaqError> {
-        let stop = Arc::new(AtomicBool::new(false));
-        let mut boards = Vec::new();
-        let mut board_threads = Vec::new();
+        let Destination {
+            topic,
+            session,
+            out,
+            events,
+            finished,
+        } = destination;
+        let StreamConfig {
+            freq, frame_size, ..
+        } = *config;
+        let completed = Arc::new(AtomicBool::new(false));
+        let epoch = Instant::now();
+        let started = SystemTime::now();
+        // anything already started is stopped and joined again when this is dropped on an error
+        let mut sampler = Sampler {
+            stop: Arc::new(AtomicBool::new(false)),
+            completed: Arc::clone(&completed),
+            remaining: None,
+            muxer_thread: None,
+            boards: Vec::new(),
+            board_threads: Vec::new(),
+            pending: Vec::new(),
+            pending_muxer: None,
+            outputs: Vec::new(),
+            started,
+            enabled: false,
+            span: span.clone(),
+        };
+        let mut board_spans = Vec::new();
         let mut receivers = Vec::new();
 
-        for config in board_configs {
-            let (tx, rx) = channel();
-            let board = Arc::new(Ai201::new(Arc::clone(&daq), freq, frame_size, config, tx)?);
+        for board_config in &config.boards {
+            let (tx, rx) = queue::bounded(
+                config.queue.depth,
+                Arc::clone(&sampler.stop),
+                Arc::clone(&stats),
+            );
+            let board_span = info_span!(
+                parent: &span,
+                "board",
+                device = board_config.device,
+                board = %backend.input_name(board_config)
+            );
+            let _entered = board_span.enter();
+            let board = Arc::clone(backend).open_input(InputSetup {
+                board_config,
+                freq,
+                frame_size,
+                epoch,
+                stats: stats.board(board_config.device),
+                out: tx,
+            })?;
+
+            sampler.boards.push(board);
+            board_spans.push(board_span.clone());
+            receivers.push((rx, board_config.channels.len()));
+        }
 
-            let cloned_stop = Arc::clone(&stop);
-            let cloned_board = Arc::clone(&board);
-            let thread = thread::spawn(move || cloned_board.sample(cloned_stop));
+        let actual_freq = sampler.actual_freq().unwrap_or(freq as f32);
+        let recorder = match &config.recording {
+            Some(recording) => Some(Arc::new(Recorder::new(
+                &topic,
+                &session,
+                recording,
+                actual_freq,
+                started,
+                config.channel_metadata(),
+            )?)),
+            None => None,
+        };
 
-            boards.push(board);
-            board_threads.push(thread);
-            receivers.push((rx, config.channels.len()));
+        for (board, board_span) in sampler.boards.iter().zip(board_spans) {
+            let cloned_stop = Arc::clone(&sampler.stop);
+            let cloned_board = Arc::clone(board);
+            sampler.pending.push(Box::new(move || {
+                board_span.in_scope(|| cloned_board.sample(cloned_stop))
+            }));
         }
 
-        let muxer_topic = topic.clone();
-        let muxer_thread = Some(if receivers.len() > 1 {
-            thread::spawn(move || merge(muxer_topic, frame_size as usize, receivers, out))
+        sampler.remaining = options
+            .scan_limit(actual_freq)
+            .map(|scans| Arc::new(AtomicU64::new(scans)));
+        let event_sink = EventSink {
+            topic: topic.clone(),
+            session: session.clone(),
+            tx: events,
+            overflow: config.queue.overflow,
+            recorder: recorder.clone(),
+            stop: Arc::clone(&sampler.stop),
+            stats: Arc::clone(&stats),
+        };
+        let sink = FrameSink {
+            topic,
+            session,
+            out,
+            events: event_sink.clone(),
+            overflow: config.queue.overflow,
+            clock: StreamClock::new(backend.timestamp_rate()),
+            unwrapper: TimestampUnwrapper::default(),
+            gaps: GapDetector::new(backend.timestamp_rate(), actual_freq),
+            remaining: sampler.remaining.clone(),
+            stop: Arc::clone(&sampler.stop),
+            completed,
+            finished,
+            recorder,
+            stats: Arc::clone(&stats),
+        };
+        let muxer_span = span.clone();
+        sampler.pending_muxer = Some(if receivers.len() > 1 {
+            Box::new(move || muxer_span.in_scope(|| merge(frame_size as usize, receivers, sink)))
         } else {
-            let (rx, _) = match receivers.pop() {
+            let (rx, chans) = match receivers.pop() {
                 Some(item) => item,
                 None => return Err(DaqError::ChannelConfigError),
             };
-            thread::spawn(move || pass_through(muxer_topic, rx, out))
+            Box::new(move || muxer_span.in_scope(|| pass_through(chans, rx, sink)))
         });
 
-        let mut outputs: Vec<Arc<Dio405>> = Vec::new();
-
-        for config in output_configs {
-            let output_board = Arc::new(Dio405::new(
-                Arc::clone(&daq),
-                topic.clone(),
-                config,
-                buzzer_out.clone(),
-            )?);
-            let cloned_stop = Arc::clone(&stop);
+        for output_config in &config.outputs {
+            let board_span = info_span!(
+                parent: &span,
+                "board",
+                device = output_config.device,
+                board = backend.output_name()
+            );
+            let _entered = board_span.enter();
+            let output_board = Arc::clone(backend).open_output(OutputSetup {
+                board_config: output_config,
+                epoch,
+                clock: StreamClock::new(backend.timestamp_rate()),
+                events: event_sink.clone(),
+            })?;
+            let cloned_stop = Arc::clone(&sampler.stop);
             let cloned_board = Arc::clone(&output_board);
-            outputs.push(output_board);
-            board_threads.push(thread::spawn(move || cloned_board.sample(cloned_stop)));
+            let cloned_span = board_span.clone();
+            sampler.outputs.push(output_board);
+            sampler.pending.push(Box::new(move || {
+                cloned_span.in_scope(|| cloned_board.sample(cloned_stop))
+            }));
         }
 
-        let bcbs: Vec<pDQBCB> = boards.iter().map(|board| board.bcb()).collect();
-        parse_err!(DqeEnable(1, bcbs.as_ptr(), bcbs.len() as i32, 1))?;
+        Ok(sampler)
+    }
+
+    /// Starts the muxer and board threads, and then acquisition on the boards.
+    pub fn run(&mut self) -> Result<(), DaqError> {
+        let span = self.span.clone();
+        let _span = span.enter();
+        self.muxer_thread = self.pending_muxer.take().map(thread::spawn);
+        for task in self.pending.drain(..) {
+            self.board_threads.push(thread::spawn(task));
+        }
 
-        Ok(Sampler {
-            stop,
-            muxer_thread,
-            boards,
-            board_threads: Some(board_threads),
-            outputs,
-        })
+        let bcbs: Vec<pDQBCB> = self.boards.iter().filter_map(|board| board.bcb()).collect();
+        if !bcbs.is_empty() {
+            parse_err!(DqeEnable(1, bcbs.as_ptr(), bcbs.len() as i32, 1))?;
+            self.enabled = true;
+        }
+        Ok(())
+    }
+
+    pub fn actual_freq(&self) -> Option<f32> {
+        self.boards.first().map(|board| board.actual_freq())
+    }
+
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Whether any of the board or muxer threads has exited by itself, e.g. because the IOM
+    /// stopped responding. They otherwise run until the sampler is dropped, or until a timed
+    /// acquisition completes.
+    pub fn failed(&self) -> bool {
+        !self.completed()
+            && self
+                .muxer_thread
+                .iter()
+                .chain(&self.board_threads)
+                .any(|thread| thread.is_finished())
+    }
+
+    /// Whether a timed acquisition has acquired all of its scans. The sampler should then be
+    /// dropped to release the hardware.
+    pub fn completed(&self) -> bool {
+        self.completed.load(Ordering::SeqCst)
+    }
+
+    /// Scans still to be acquired, if this is a timed acquisition.
+    pub fn remaining(&self) -> Option<u64> {
+        self.remaining
+            .as_ref()
+            .map(|remaining| remaining.load(Ordering::SeqCst))
     }
 
     pub async fn trigger(&mut self) -> Result<(), DaqError> {
         for output in self.outputs.as_slice() {
-            output.trigger().await?;
+            output.write(0xffffffff)?;
+            sleep(Duration::from_millis(100)).await;
+            output.write(0x0)?;
         }
         Ok(())
     }
 }
 
-fn pass_through(
+/// Where output boards report their events. Events are also recorded if the stream is being
+/// recorded.
+#[derive(Clone)]
+pub(crate) struct EventSink {
     topic: String,
-    input: Receiver<(Vec<f64>, Vec<u32>)>,
-    out: UnboundedSender<(String, Vec<f64>, Vec<u32>)>,
-) {
-    loop {
-        let (data, timestamps) = match input.recv() {
-            Ok(buf) => buf,
-            Err(_) => {
-                // channel closed -- time to shut down
-                break;
+    session: String,
+    tx: Sender<EventData>,
+    overflow: OverflowPolicy,
+    recorder: Option<Arc<Recorder>>,
+    stop: Arc<AtomicBool>,
+    stats: Arc<StreamStats>,
+}
+
+impl EventSink {
+    pub(crate) fn send(&self, event: StreamEvent) -> Result<(), SendError<()>> {
+        if let StreamEvent::Buzzer { .. } = event {
+            self.stats.buzzer_events.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record_event(&event);
+        }
+        queue::publish(
+            &self.tx,
+            EventData {
+                stream: self.topic.clone(),
+                session: self.session.clone(),
+                event,
+            },
+            self.overflow,
+            &self.stats.events_dropped,
+            Some(&self.stop),
+        )
+        .map_err(|_| SendError(()))
+    }
+}
+
+/// Hands the muxer's frames to the publisher, unwrapping the raw timestamps as it goes and
+/// reporting any gaps between them.
+struct FrameSink {
+    topic: String,
+    session: String,
+    out: Sender<SensorData>,
+    events: EventSink,
+    overflow: OverflowPolicy,
+    clock: StreamClock,
+    unwrapper: TimestampUnwrapper,
+    gaps: GapDetector,
+    /// Scans left to acquire in a timed acquisition, shared with the sampler.
+    remaining: Option<Arc<AtomicU64>>,
+    stop: Arc<AtomicBool>,
+    completed: Arc<AtomicBool>,
+    finished: UnboundedSender<String>,
+    recorder: Option<Arc<Recorder>>,
+    stats: Arc<StreamStats>,
+}
+
+impl FrameSink {
+    /// Returns false once a timed acquisition is complete, after which the muxer should exit. The
+    /// last frame is cut short so that exactly the requested number of scans are sent.
+    fn send(&mut self, channels: usize, mut data: Vec<f64>, mut timestamps: Vec<u32>) -> bool {
+        let last = match &self.remaining {
+            Some(remaining) => {
+                let left = remaining.load(Ordering::SeqCst);
+                let scans = cmp::min(left, timestamps.len() as u64);
+                remaining.store(left - scans, Ordering::SeqCst);
+                timestamps.truncate(scans as usize);
+                data.truncate(scans as usize * channels);
+                left == scans
             }
+            None => false,
         };
-        if data.len() != timestamps.len() {
-            eprintln!("Buffers differ in length.");
-            break;
+        let ticks: Vec<u64> = timestamps
+            .iter()
+            .map(|&raw| self.unwrapper.unwrap(raw))
+            .collect();
+        if let Some(&last) = ticks.last() {
+            self.clock.observe(last);
         }
-        match out.send((topic.clone(), data, timestamps)) {
-            Ok(_) => (),
-            Err(err) => eprintln!("Failed to push buffer to channel. Error: {}", err),
+        let unix_ns = ticks
+            .iter()
+            .map(|&ticks| self.clock.unix_ns(ticks))
+            .collect();
+        let sequence = self.stats.frames.fetch_add(1, Ordering::Relaxed);
+        for (from, to, missing_scans) in self.gaps.check(&ticks) {
+            self.stats.data_gaps.fetch_add(1, Ordering::Relaxed);
+            warn!(sequence, from, to, missing_scans, "Gap in sensor data");
+            let gap = StreamEvent::DataGap {
+                sequence,
+                from_ticks: from,
+                from_unix_ns: self.clock.unix_ns(from),
+                to_ticks: to,
+                to_unix_ns: self.clock.unix_ns(to),
+                missing_scans,
+            };
+            if let Err(err) = self.events.send(gap) {
+                error!(error = %err, "Failed to push data gap event to channel");
+            }
+        }
+        let frame = SensorData {
+            stream: self.topic.clone(),
+            session: self.session.clone(),
+            sequence,
+            channels,
+            data,
+            timestamps,
+            ticks,
+            unix_ns,
         };
+        if let Some(recorder) = &self.recorder {
+            recorder.record_frame(&frame);
+        }
+        if let Err(err) = queue::publish(
+            &self.out,
+            frame,
+            self.overflow,
+            &self.stats.frames_dropped,
+            Some(&self.stop),
+        ) {
+            // frames still queued when the stream is stopped are dropped
+            if !self.stop.load(Ordering::SeqCst) {
+                error!(error = %err, "Failed to push frame to channel");
+            }
+        }
+        if last {
+            self.finish();
+        }
+        !last
+    }
+
+    /// Stops the boards and tells the stream's manager that the acquisition is complete.
+    fn finish(&self) {
+        info!("Timed acquisition complete");
+        self.completed.store(true, Ordering::SeqCst);
+        self.stop.store(true, Ordering::SeqCst);
+        if self.finished.send(self.topic.clone()).is_err() {
+            error!("Failed to report completed acquisition");
+        }
     }
 }
 
-fn merge(
-    topic: String,
-    frames: usize,
-    inputs: Vec<(Receiver<(Vec<f64>, Vec<u32>)>, usize)>,
-    out: UnboundedSender<(String, Vec<f64>, Vec<u32>)>,
-) {
+/// Finds scans that are further apart than the sample rate allows, which means the scans between
+/// them were lost, e.g. to `DQ_ePacketLost` or a frame whose timestamps couldn't be decoded.
+struct GapDetector {
+    /// Expected ticks between consecutive scans.
+    period: f64,
+    last: Option<u64>,
+}
+
+impl GapDetector {
+    fn new(timestamp_rate: u64, freq: f32) -> Self {
+        GapDetector {
+            period: timestamp_rate as f64 / freq as f64,
+            last: None,
+        }
+    }
+
+    /// Checks a frame's unwrapped timestamps against each other and the end of the previous frame.
+    /// Returns the ticks of the scans either side of each gap, and how many scans are missing.
+    fn check(&mut self, ticks: &[u64]) -> Vec<(u64, u64, u64)> {
+        let mut gaps = Vec::new();
+        for &tick in ticks {
+            if let Some(last) = self.last {
+                // allow for jitter of up to half a scan
+                let scans = (tick.saturating_sub(last) as f64 / self.period).round() as u64;
+                if scans > 1 {
+                    gaps.push((last, tick, scans - 1));
+                }
+            }
+            self.last = Some(tick);
+        }
+        gaps
+    }
+}
+
+fn pass_through(chans: usize, mut input: BoundedReceiver<RawFrame>, mut sink: FrameSink) {
+    // runs until the channel is closed
+    while let Some((data, timestamps)) = input.recv() {
+        // 2 extra values per scan for the timestamp
+        if data.len() / (chans + 2) != timestamps.len() {
+            error!("Buffers differ in length");
+            break;
+        }
+        let data = data
+            .chunks(chans + 2)
+            .flat_map(|scan| scan[..chans].iter().copied())
+            .collect();
+        if !sink.send(chans, data, timestamps) {
+            break;
+        }
+    }
+}
+
+fn merge(frames: usize, mut inputs: Vec<(BoundedReceiver<RawFrame>, usize)>, mut sink: FrameSink) {
     let total_channels = inputs.iter().fold(0, |total, (_, chans)| total + chans);
     loop {
         let mut combined: Vec<f64> = vec![0.0; total_channels * frames];
 
-        let messages: Vec<((Vec<f64>, Vec<u32>), &usize)> = match inputs
-            .iter()
-            .map(|(input, chans)| {
-                Ok::<((Vec<f64>, Vec<u32>), &usize), RecvError>((input.recv()?, chans))
-            })
-            .collect()
-        {
-            Ok(bufs) => bufs,
-            Err(_) => {
+        let mut data_buffers: Vec<(Vec<f64>, usize)> = Vec::with_capacity(inputs.len());
+        let mut timestamp_buffers: Vec<Vec<u32>> = Vec::with_capacity(inputs.len());
+        for (input, chans) in &mut inputs {
+            match input.recv() {
+                Some((data, timestamps)) => {
+                    data_buffers.push((data, *chans));
+                    timestamp_buffers.push(timestamps);
+                }
                 // one of the channels has been closed -- time to shut down
-                break;
+                None => return,
             }
-        };
-
-        let (data_buffers, timestamp_buffers): (Vec<(Vec<f64>, &usize)>, Vec<Vec<u32>>) = messages
-            .into_iter()
-            .map(
-                |((data, timestamps), chans)| -> ((Vec<f64>, &usize), Vec<u32>) {
-                    ((data, chans), timestamps)
-                },
-            )
-            .unzip();
+        }
 
         if !data_buffers
             .iter()
-            .map(|(v, chans)| v.len() / (*chans + 2)) // 2 extra channels for timestamp
+            .map(|(v, chans)| v.len() / (chans + 2)) // 2 extra channels for timestamp
             .chain(timestamp_buffers.iter().map(|v| v.len()))
             .all_equal()
         {
-            eprintln!("Buffers differ in length.");
+            error!("Buffers differ in length");
             break;
         }
 
@@ -170,7 +493,7 @@ fn merge(
         //     break;
         // }
 
-        let timestamps = match timestamp_buffers.into_iter().nth(0) {
+        let timestamps = match timestamp_buffers.into_iter().next() {
             Some(buf) => buf,
             None => {
                 break;
@@ -180,7 +503,8 @@ fn merge(
         // danger here! memcpy-style pointer arithmetic!
         for i in 0..frames {
             let mut dst_start = i * total_channels;
-            for (buf, &chans) in &data_buffers {
+            for (buf, chans) in &data_buffers {
+                let chans = *chans;
                 let src_start = i * (chans + 2); // 2 extra values (timestamps)
                 let src_end = src_start + chans;
                 let dst_end = dst_start + chans;
@@ -188,40 +512,154 @@ fn merge(
                 dst_start += chans;
             }
         }
-        match out.send((topic.clone(), combined, timestamps)) {
-            Ok(_) => (),
-            Err(err) => eprintln!("Failed to push merged buffer to channel. Error: {}", err),
-        };
+        if !sink.send(total_channels, combined, timestamps) {
+            break;
+        }
     }
 }
 
 impl Drop for Sampler {
     fn drop(&mut self) {
-        let bcbs: Vec<pDQBCB> = self.boards.iter().map(|board| board.bcb()).collect();
-        match parse_err!(DqeEnable(0, bcbs.as_ptr(), bcbs.len() as i32, 1)) {
-            Ok(_) => (),
-            Err(err) => eprintln!("DqeEnable -> false failed. Error: {:?}", err),
-        };
+        let _span = self.span.enter();
+        let bcbs: Vec<pDQBCB> = self.boards.iter().filter_map(|board| board.bcb()).collect();
+        if self.enabled {
+            match parse_err!(DqeEnable(0, bcbs.as_ptr(), bcbs.len() as i32, 1)) {
+                Ok(_) => (),
+                Err(err) => error!(error = ?err, "DqeEnable -> false failed"),
+            };
+        }
         self.stop.store(true, Ordering::SeqCst);
-        match self.board_threads.take() {
-            Some(threads) => {
-                for thread in threads {
-                    match thread.join() {
-                        Ok(_) => (),
-                        Err(_) => eprintln!("Failed to join board thread."),
-                    };
-                }
-            }
-            None => eprintln!("No board threads to join."),
+        for thread in self.board_threads.drain(..) {
+            match thread.join() {
+                Ok(_) => (),
+                Err(_) => error!("Failed to join board thread"),
+            };
         }
         self.boards.clear();
         self.outputs.clear();
-        match self.muxer_thread.take() {
-            Some(handle) => match handle.join() {
-                Ok(_) => (),
-                Err(_) => eprintln!("Failed to join sampling thread."),
+        // there is no muxer thread if the sampler failed to start
+        if let Some(handle) = self.muxer_thread.take() {
+            if handle.join().is_err() {
+                error!("Failed to join sampling thread");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    /// Publishes merged frames to `out`, dropping them if it is full.
+    fn frame_sink(
+        out: Sender<SensorData>,
+        events: Sender<EventData>,
+        finished: UnboundedSender<String>,
+        stats: Arc<StreamStats>,
+    ) -> FrameSink {
+        let stop = Arc::new(AtomicBool::new(false));
+        FrameSink {
+            topic: String::from("s"),
+            session: String::from("session"),
+            out,
+            events: EventSink {
+                topic: String::from("s"),
+                session: String::from("session"),
+                tx: events,
+                overflow: OverflowPolicy::DropNewest,
+                recorder: None,
+                stop: Arc::clone(&stop),
+                stats: Arc::clone(&stats),
             },
-            None => eprintln!("No muxer thread to join!!"),
+            overflow: OverflowPolicy::DropNewest,
+            clock: StreamClock::new(1_000_000),
+            unwrapper: TimestampUnwrapper::default(),
+            gaps: detector(),
+            remaining: None,
+            stop,
+            completed: Arc::new(AtomicBool::new(false)),
+            finished,
+            recorder: None,
+            stats,
+        }
+    }
+
+    /// Frame `index` of two scans of a single channel, both reading `value`.
+    fn raw_frame(index: u32, value: f64) -> RawFrame {
+        (
+            vec![value, 0.0, 0.0, value, 0.0, 0.0],
+            vec![index * 2000, index * 2000 + 1000],
+        )
+    }
+
+    #[test]
+    fn merges_boards_without_dropping_frames_from_either() {
+        let stats = Arc::new(StreamStats::default());
+        let (out, mut merged) = mpsc::channel(64);
+        let (events, _events) = mpsc::channel(64);
+        let (finished, _finished) = mpsc::unbounded_channel();
+        let sink = frame_sink(out, events, finished, Arc::clone(&stats));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (fast_tx, fast_rx) = queue::bounded(1, Arc::clone(&stop), Arc::clone(&stats));
+        let (slow_tx, slow_rx) = queue::bounded(1, stop, Arc::clone(&stats));
+        let muxer = thread::spawn(move || merge(2, vec![(fast_rx, 1), (slow_rx, 1)], sink));
+
+        // the fast board fills its queue while the slow one is still reading its first frame
+        let fast = thread::spawn(move || {
+            for index in 0..5 {
+                fast_tx.send(raw_frame(index, index as f64)).unwrap();
+            }
+        });
+        for index in 0..5 {
+            thread::sleep(Duration::from_millis(5));
+            slow_tx
+                .send(raw_frame(index, 100.0 + index as f64))
+                .unwrap();
+        }
+        fast.join().unwrap();
+        drop(slow_tx);
+        muxer.join().unwrap();
+
+        for index in 0..5 {
+            let frame = merged.try_recv().unwrap();
+            let fast = index as f64;
+            assert_eq!(frame.data, [fast, 100.0 + fast, fast, 100.0 + fast]);
         }
+        assert!(merged.try_recv().is_err());
+        assert_eq!(stats.frames_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    /// 1000 ticks between scans.
+    fn detector() -> GapDetector {
+        GapDetector::new(1_000_000, 1000.0)
+    }
+
+    #[test]
+    fn finds_no_gaps_in_contiguous_scans() {
+        let mut gaps = detector();
+        assert!(gaps.check(&[0, 1000, 2000]).is_empty());
+        assert!(gaps.check(&[3000, 4000]).is_empty());
+        assert!(gaps.check(&[]).is_empty());
+        assert!(gaps.check(&[5000]).is_empty());
+    }
+
+    #[test]
+    fn tolerates_jitter_of_under_half_a_scan() {
+        let mut gaps = detector();
+        assert!(gaps.check(&[0, 1400, 2000, 3499]).is_empty());
+    }
+
+    #[test]
+    fn finds_gaps_within_and_between_frames() {
+        let mut gaps = detector();
+        assert_eq!(gaps.check(&[0, 1000, 4000]), [(1000, 4000, 2)]);
+        assert_eq!(gaps.check(&[5600, 6000]), [(4000, 5600, 1)]);
+    }
+
+    #[test]
+    fn ignores_timestamps_that_go_backwards() {
+        let mut gaps = detector();
+        assert!(gaps.check(&[5000, 1000, 2000]).is_empty());
     }
 }
@@ -1,22 +1,29 @@
-use crate::dataframe_generated::daiquiri::{
-    BuzzerEvent, BuzzerEventArgs, DaiquiriData, DaiquiriDataArgs, Event, SensorFrame,
-    SensorFrameArgs,
-};
-use flatbuffers;
-use powerdna::{config::StreamConfig, daq::Daq, engine::DqEngine, DaqError, SignalManager};
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use rdkafka::ClientConfig;
-use serde_json;
-use std::collections::HashMap;
+use crate::encode;
+use crate::kafka::{self, MessageKind, Route};
+use crate::live::{LiveChannel, LiveData, LiveStreams};
+use crate::metrics::KafkaMetrics;
+use crate::scheduler::{self, ScheduleStore};
+use crate::spool::{self, Spool, SpoolConfig};
+use crate::SignalStore;
+use powerdna::config::{validate, ConfigProblem, StreamConfig};
+use powerdna::daq::simulated::SimulatedDaq;
+use powerdna::daq::{Daq, DaqBackend};
+use powerdna::inventory::Inventory;
+use powerdna::{engine::DqEngine, DaqError, EventData, SensorData, SignalManager};
+use rdkafka::producer::FutureProducer;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter, Write};
+use std::panic;
 use std::sync::Arc;
-use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedSender};
 use tokio::sync::Mutex;
+use tokio::task;
+use tracing::{info, info_span};
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
@@ -30,6 +37,8 @@ pub enum ConfigError {
         #[from]
         source: serde_json::Error,
     },
+    #[error("Invalid stream config.{}", format_problems(.problems))]
+    ValidationError { problems: Vec<ConfigProblem> },
     #[error("CLOCK_PERIOD invalid.")]
     InvalidClockPeriod,
     #[error("Failed to initialise DAQ.")]
@@ -37,6 +46,14 @@ pub enum ConfigError {
         #[from]
         source: DaqError,
     },
+    #[error("Invalid Kafka setting {property}: {message}")]
+    InvalidKafkaSetting { property: String, message: String },
+    #[error("Invalid spool setting {variable}: {message}")]
+    InvalidSpoolSetting { variable: String, message: String },
+    #[error("Invalid health check setting {variable}: {message}")]
+    InvalidHealthSetting { variable: String, message: String },
+    #[error("Failed to open Kafka spool: {source}")]
+    SpoolError { source: io::Error },
     #[error("Failed to connect to Kafka.")]
     KafkaError {
         #[from]
@@ -44,84 +61,361 @@ pub enum ConfigError {
     },
 }
 
-async fn publish_buzzer_events(
-    producer: &FutureProducer,
-    mut rx: UnboundedReceiver<(String, u32)>,
+fn format_problems(problems: &[ConfigProblem]) -> String {
+    problems
+        .iter()
+        .map(|problem| format!("\n  {}", problem))
+        .collect()
+}
+
+/// Everything a stream's publisher tasks need to deliver its frames and events.
+#[derive(Clone)]
+pub struct Publisher {
+    producer: FutureProducer,
+    pub spool: Arc<Spool>,
+    pub metrics: Arc<KafkaMetrics>,
+    live: LiveStreams,
+}
+
+impl Publisher {
+    /// Spawns the publisher tasks for a stream, returning the sending ends of their queues. The
+    /// tasks finish once the stream's senders are dropped.
+    fn spawn(&self, name: &str, config: &StreamConfig) -> (Sender<SensorData>, Sender<EventData>) {
+        let route = Route::new(name, config);
+        let live = Arc::new(self.live.open(name));
+        let (sensor_tx, sensor_rx) = mpsc::channel(config.queue.depth);
+        tokio::spawn(publish_sensor_data(
+            self.clone(),
+            route.clone(),
+            Arc::clone(&live),
+            sensor_rx,
+        ));
+        let (event_tx, event_rx) = mpsc::channel(config.queue.depth);
+        tokio::spawn(publish_events(self.clone(), route, live, event_rx));
+        (sensor_tx, event_tx)
+    }
+}
+
+async fn publish_events(
+    publisher: Publisher,
+    route: Route,
+    live: Arc<LiveChannel>,
+    mut rx: Receiver<EventData>,
 ) {
     loop {
-        let mut builder = flatbuffers::FlatBufferBuilder::new();
-        let (topic, timestamp) = match rx.recv().await {
-            Some(val) => val,
+        let event = match rx.recv().await {
+            Some(val) => Arc::new(val),
             None => break,
         };
-        let buzzer_event = BuzzerEvent::create(&mut builder, &BuzzerEventArgs { timestamp });
-        let data = DaiquiriData::create(
-            &mut builder,
-            &DaiquiriDataArgs {
-                event_type: Event::BuzzerEvent,
-                event: Some(buzzer_event.as_union_value()),
-            },
+        let payload = encode::event(&event.event);
+        let message = route.message(
+            MessageKind::Event,
+            encode::event_type(&event.event),
+            &event.session,
+            payload,
         );
-        builder.finish(data, None);
-        match producer
-            .send(
-                FutureRecord::to(topic.as_str())
-                    .key(topic.as_str())
-                    .payload(builder.finished_data()),
-                Duration::from_secs(180),
-            )
-            .await
-        {
-            Ok(_) => (),
-            Err((err, _)) => eprintln!("Failed to send to Kafka. Error: {}", err),
-        };
+        live.send(LiveData::Event(event));
+        spool::deliver(
+            &publisher.producer,
+            &publisher.spool,
+            &publisher.metrics,
+            message,
+        )
+        .await;
     }
 }
 
 async fn publish_sensor_data(
-    producer: &FutureProducer,
-    mut rx: UnboundedReceiver<(String, Vec<f64>, Vec<u32>)>,
+    publisher: Publisher,
+    route: Route,
+    live: Arc<LiveChannel>,
+    mut rx: Receiver<SensorData>,
 ) {
     loop {
-        let mut builder = flatbuffers::FlatBufferBuilder::new();
-        let (topic, data, timestamps) = match rx.recv().await {
-            Some(val) => val,
+        let sensor_data = match rx.recv().await {
+            Some(val) => Arc::new(val),
             None => break,
         };
-        let timestamps = Some(builder.create_vector(timestamps.as_slice()));
-        let frame = Some(builder.create_vector(data.as_slice()));
-        let sensor_frame = SensorFrame::create(
-            &mut builder,
-            &SensorFrameArgs {
-                timestamps,
-                frame,
-                ..Default::default()
-            },
+        let payload = encode::sensor_data(&sensor_data);
+        live.send(LiveData::Frame(Arc::clone(&sensor_data)));
+        let message = route.message(
+            MessageKind::Sensor,
+            "SensorFrame",
+            &sensor_data.session,
+            payload,
+        );
+        spool::deliver(
+            &publisher.producer,
+            &publisher.spool,
+            &publisher.metrics,
+            message,
+        )
+        .await;
+    }
+}
+
+/// Outcome of reloading the config file. Streams are listed by name.
+#[derive(Serialize, Default)]
+pub struct ReloadReport {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    /// Changed or removed in the file, but left as they are because they are running.
+    pub skipped: Vec<String>,
+    pub failed: Vec<FailedStream>,
+}
+
+#[derive(Serialize)]
+pub struct FailedStream {
+    pub name: String,
+    pub error: String,
+}
+
+/// Creates streams, both at startup and through the API.
+#[derive(Clone)]
+pub struct StreamFactory {
+    engine: Arc<DqEngine>,
+    pub publisher: Publisher,
+    config_path: String,
+    /// Write the streams back to `config_path` whenever they are changed through the API.
+    persist: bool,
+    /// Where streams report completed timed acquisitions, for the supervisor to stop them.
+    finished: UnboundedSender<String>,
+}
+
+impl StreamFactory {
+    /// Opens the stream's IOM and spawns its publisher. The IOM is closed again when the returned
+    /// manager is dropped.
+    pub fn create(&self, name: String, config: StreamConfig) -> Result<SignalManager, DaqError> {
+        let backend = info_span!("stream", stream = %name, iom = %config.ip)
+            .in_scope(|| self.open(&name, &config))?;
+        Ok(self.manage(name, config, backend))
+    }
+
+    /// Spawns the publisher of a stream whose IOM has already been opened.
+    pub fn manage(&self, name: String, config: StreamConfig, backend: DaqBackend) -> SignalManager {
+        info!(
+            stream = %name,
+            boards = config.boards.len(),
+            outputs = config.outputs.len(),
+            "Stream configured"
         );
-        let data = DaiquiriData::create(
-            &mut builder,
-            &DaiquiriDataArgs {
-                event_type: Event::SensorFrame,
-                event: Some(sensor_frame.as_union_value()),
-            },
+        let (sensor_tx, event_tx) = self.publisher.spawn(&name, &config);
+        SignalManager::new(
+            name,
+            config,
+            backend,
+            sensor_tx,
+            event_tx,
+            self.finished.clone(),
+            None,
+        )
+    }
+
+    /// Opens and checks the stream's IOM, or sets up its simulation.
+    pub fn open(&self, name: &str, config: &StreamConfig) -> Result<DaqBackend, DaqError> {
+        Ok(match &config.simulation {
+            Some(simulation) => Arc::new(SimulatedDaq::new(simulation.clone())),
+            None => {
+                let daq = Daq::new(Arc::clone(&self.engine), config.ip.clone())?;
+                daq.verify_layers(name, config)?;
+                Arc::new(daq)
+            }
+        })
+    }
+
+    /// Re-reads the config file and brings the streams in line with it. Streams whose config is
+    /// unchanged are left alone, as are running streams, so that their IOM connections survive.
+    /// IOMs are opened without holding the streams, since that can take a while. The schedules of
+    /// changed and removed streams are cancelled, as they were made for the old config.
+    pub async fn reload(
+        &self,
+        store: &SignalStore,
+        schedules: &ScheduleStore,
+    ) -> Result<ReloadReport, ConfigError> {
+        let configs = read_config(&self.config_path)?;
+        let plan = plan_reload(&*store.lock().await, configs);
+
+        let opener = self.clone();
+        let opened = task::spawn_blocking(move || {
+            plan.open
+                .into_iter()
+                .map(|(name, config, added)| {
+                    let span = info_span!("stream", stream = %name, iom = %config.ip);
+                    let backend = span.in_scope(|| opener.open(&name, &config));
+                    (name, config, added, backend)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()));
+
+        let mut streams = store.lock().await;
+        let mut report = ReloadReport {
+            skipped: plan.skipped,
+            ..ReloadReport::default()
+        };
+        // streams may have been started while the IOMs were being opened
+        for name in plan.removed {
+            match streams.get(&name).map(|manager| manager.status().running) {
+                Some(true) => report.skipped.push(name),
+                Some(false) => {
+                    streams.remove(&name);
+                    report.removed.push(name);
+                }
+                None => (),
+            }
+        }
+        for (name, config, added, backend) in opened {
+            if let Some(manager) = streams.get(&name) {
+                if manager.status().running {
+                    report.skipped.push(name);
+                    continue;
+                }
+            }
+            match backend {
+                Ok(backend) => {
+                    let manager = self.manage(name.clone(), config, backend);
+                    streams.insert(name.clone(), manager);
+                    match added {
+                        true => report.added.push(name),
+                        false => report.changed.push(name),
+                    }
+                }
+                Err(err) => report.failed.push(FailedStream {
+                    name,
+                    error: err.to_string(),
+                }),
+            }
+        }
+
+        for name in report.changed.iter().chain(&report.removed) {
+            scheduler::cancel_stream(schedules, name).await;
+        }
+
+        info!(
+            added = ?report.added,
+            changed = ?report.changed,
+            removed = ?report.removed,
+            skipped = ?report.skipped,
+            failed = report.failed.len(),
+            "Reloaded stream config"
         );
-        builder.finish(data, None);
-        match producer
-            .send(
-                FutureRecord::to(topic.as_str())
-                    .key(topic.as_str())
-                    .payload(builder.finished_data()),
-                Duration::from_secs(180),
-            )
-            .await
-        {
-            Ok(_) => (),
-            Err((err, _)) => eprintln!("Failed to send to Kafka. Error: {}", err),
+        Ok(report)
+    }
+
+    /// Prepares to read the inventory of an IOM over a short-lived connection of its own, so that
+    /// it never shares a command handle with a stream, even one started while it is being read.
+    /// The IOM of a running stream isn't asked, so as not to disturb its acquisition. The returned
+    /// closure talks to the IOM, so it should be run on a blocking thread once the streams have
+    /// been unlocked.
+    pub fn inventory(
+        &self,
+        streams: &HashMap<String, SignalManager>,
+        ip: &str,
+    ) -> Result<impl FnOnce() -> Result<Inventory, DaqError> + Send + 'static, DaqError> {
+        let running = streams.values().any(|manager| {
+            manager.config().simulation.is_none()
+                && manager.config().ip == ip
+                && manager.status().running
+        });
+        if running {
+            return Err(DaqError::StreamStateError);
+        }
+        let engine = Arc::clone(&self.engine);
+        let ip = ip.to_string();
+        Ok(move || Ok(Daq::new(engine, ip)?.inventory()?))
+    }
+
+    /// Writes the given stream configs to the config file if `PERSIST_STREAMS` is set. The file is
+    /// replaced in one go so that a crash part way through doesn't leave it truncated. Callers
+    /// persist the streams as they are about to be, so that a failure leaves them unchanged.
+    pub fn persist(&self, configs: &HashMap<String, StreamConfig>) -> Result<(), io::Error> {
+        if !self.persist {
+            return Ok(());
+        }
+        let configs: BTreeMap<&String, &StreamConfig> = configs.iter().collect();
+        let temp_path = format!("{}.tmp", self.config_path);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer_pretty(&mut writer, &configs)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        fs::rename(&temp_path, &self.config_path)?;
+        info!(path = %self.config_path, "Saved stream config");
+        Ok(())
+    }
+}
+
+/// How reloading the config file changes the streams.
+#[derive(Default)]
+struct ReloadPlan {
+    /// Streams to create or replace, with whether they are new.
+    open: Vec<(String, StreamConfig, bool)>,
+    removed: Vec<String>,
+    /// Changed or removed in the file, but running.
+    skipped: Vec<String>,
+}
+
+fn plan_reload(
+    streams: &HashMap<String, SignalManager>,
+    mut configs: HashMap<String, StreamConfig>,
+) -> ReloadPlan {
+    let mut plan = ReloadPlan::default();
+
+    let mut removed: Vec<String> = streams
+        .keys()
+        .filter(|name| !configs.contains_key(*name))
+        .cloned()
+        .collect();
+    removed.sort();
+    for name in removed {
+        match streams[&name].status().running {
+            true => plan.skipped.push(name),
+            false => plan.removed.push(name),
+        }
+    }
+
+    let mut names: Vec<String> = configs.keys().cloned().collect();
+    names.sort();
+    for name in names {
+        let config = match configs.remove(&name) {
+            Some(config) => config,
+            None => continue,
+        };
+        let added = match streams.get(&name) {
+            None => true,
+            Some(manager) if *manager.config() == config => continue,
+            Some(manager) if manager.status().running => {
+                plan.skipped.push(name);
+                continue;
+            }
+            Some(_) => false,
         };
+        plan.open.push((name, config, added));
     }
+    plan
+}
+
+/// The config of every stream, by name.
+pub fn configs(streams: &HashMap<String, SignalManager>) -> HashMap<String, StreamConfig> {
+    streams
+        .iter()
+        .map(|(name, manager)| (name.clone(), manager.config().clone()))
+        .collect()
+}
+
+fn read_config(path: &str) -> Result<HashMap<String, StreamConfig>, ConfigError> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let config: HashMap<String, StreamConfig> = serde_json::from_reader(reader)?;
+    validate(&config).map_err(|problems| ConfigError::ValidationError { problems })?;
+    Ok(config)
 }
 
-pub fn initialise() -> Result<Arc<Mutex<HashMap<String, SignalManager>>>, ConfigError> {
+pub fn initialise(
+    live: LiveStreams,
+    finished: UnboundedSender<String>,
+) -> Result<(SignalStore, StreamFactory), ConfigError> {
     let clock_period: u32 = match env::var("CLOCK_PERIOD")
         .unwrap_or(String::from("1000"))
         .parse()
@@ -131,50 +425,122 @@ pub fn initialise() -> Result<Arc<Mutex<HashMap<String, SignalManager>>>, Config
     };
     let file_path = env::var("STREAM_CONFIG").unwrap_or(String::from("/etc/daiquiri/streams.json"));
 
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-    let mut config: HashMap<String, StreamConfig> = serde_json::from_reader(reader)?;
+    let persist = matches!(env::var("PERSIST_STREAMS").as_deref(), Ok("true") | Ok("1"));
 
-    // TODO validate config -- no repeated stream names, IPs, etc.
+    let mut config = read_config(&file_path)?;
 
     let engine = Arc::new(DqEngine::new(clock_period)?);
 
-    let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", "host.docker.internal:19092")
-        .set("message.timeout.ms", "5000")
-        .create()?;
+    let producer: FutureProducer = kafka::producer_config()?.create()?;
+    let spool = Arc::new(
+        Spool::open(SpoolConfig::from_env()?)
+            .map_err(|source| ConfigError::SpoolError { source })?,
+    );
+    let metrics = Arc::new(KafkaMetrics::default());
+    tokio::spawn(spool::replay(
+        producer.clone(),
+        Arc::clone(&spool),
+        Arc::clone(&metrics),
+    ));
+
+    let publisher = Publisher {
+        producer,
+        spool,
+        metrics,
+        live,
+    };
 
-    let (sensor_tx, sensor_rx) = tokio::sync::mpsc::unbounded_channel();
-    let sensor_producer = producer.clone();
-    tokio::spawn(async move { publish_sensor_data(&sensor_producer, sensor_rx).await });
-    let (buzzer_tx, buzzer_rx) = tokio::sync::mpsc::unbounded_channel();
-    tokio::spawn(async move { publish_buzzer_events(&producer, buzzer_rx).await });
+    let factory = StreamFactory {
+        engine,
+        publisher,
+        config_path: file_path,
+        persist,
+        finished,
+    };
 
     let streams = config
         .drain()
-        .map(|(name, config)| {
-            let StreamConfig {
-                ip,
-                freq,
-                frame_size,
-                boards,
-                outputs,
-            } = config;
-            let daq = Arc::new(Daq::new(engine.clone(), ip.clone())?);
-            let manager = SignalManager::new(
-                name.clone(),
-                freq,
-                frame_size,
-                boards,
-                outputs,
-                daq,
-                sensor_tx.clone(),
-                buzzer_tx.clone(),
-                None,
-            );
-            Ok((name, manager))
-        })
-        .collect::<Result<HashMap<String, SignalManager>, DaqError>>()?;
+        .map(|(name, config)| Ok((name.clone(), factory.create(name, config)?)))
+        .collect::<Result<HashMap<String, SignalManager>, DaqError>>()
+        .map_err(|err| match err {
+            DaqError::HardwareMismatchError { problems } => {
+                ConfigError::ValidationError { problems }
+            }
+            err => ConfigError::from(err),
+        })?;
+
+    Ok((Arc::new(Mutex::new(streams)), factory))
+}
 
-    Ok(Arc::new(Mutex::new(streams)))
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use powerdna::session::StartOptions;
+    use serde_json::json;
+
+    pub(crate) fn config(freq: u32) -> StreamConfig {
+        serde_json::from_value(json!({
+            "ip": "192.168.100.2",
+            "freq": freq,
+            "frame_size": 10,
+            "boards": [{ "device": 0, "channels": [{ "id": 0, "gain": 1 }] }],
+            "outputs": [],
+            "simulation": {},
+        }))
+        .unwrap()
+    }
+
+    /// A stream on the simulated backend, whose queues are left for the test to drain.
+    pub(crate) fn manager(
+        name: &str,
+        freq: u32,
+    ) -> (SignalManager, Receiver<SensorData>, Receiver<EventData>) {
+        let config = config(freq);
+        let simulation = config.simulation.clone().unwrap();
+        let (sensor_tx, sensor_rx) = mpsc::channel(64);
+        let (event_tx, event_rx) = mpsc::channel(64);
+        let (finished, _) = mpsc::unbounded_channel();
+        let manager = SignalManager::new(
+            name.to_string(),
+            config,
+            Arc::new(SimulatedDaq::new(simulation)),
+            sensor_tx,
+            event_tx,
+            finished,
+            None,
+        );
+        (manager, sensor_rx, event_rx)
+    }
+
+    #[tokio::test]
+    async fn plans_a_reload_around_running_streams() {
+        let mut streams = HashMap::new();
+        let mut queues = Vec::new();
+        for name in ["busy", "busy-gone", "changed", "gone", "same"] {
+            let (manager, sensor_rx, event_rx) = manager(name, 1000);
+            streams.insert(name.to_string(), manager);
+            queues.push((sensor_rx, event_rx));
+        }
+        for name in ["busy", "busy-gone"] {
+            let manager = streams.get_mut(name).unwrap();
+            manager.start(StartOptions::default()).await.unwrap();
+        }
+        let configs = HashMap::from([
+            (String::from("busy"), config(2000)),
+            (String::from("changed"), config(2000)),
+            (String::from("new"), config(1000)),
+            (String::from("same"), config(1000)),
+        ]);
+
+        let plan = plan_reload(&streams, configs);
+        let open: Vec<(&str, bool)> = plan
+            .open
+            .iter()
+            .map(|(name, _, added)| (name.as_str(), *added))
+            .collect();
+        assert_eq!(open, [("changed", false), ("new", true)]);
+        assert_eq!(plan.open[0].1.freq, 2000);
+        assert_eq!(plan.removed, ["gone"]);
+        assert_eq!(plan.skipped, ["busy-gone", "busy"]);
+    }
 }
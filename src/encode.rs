@@ -0,0 +1,179 @@
+use crate::dataframe_generated::daiquiri::{
+    BuzzerEvent, BuzzerEventArgs, ChannelInfo, ChannelInfoArgs, DaiquiriData, DaiquiriDataArgs,
+    DataGap, DataGapArgs, Event, SensorFrame, SensorFrameArgs, SessionStarted, SessionStartedArgs,
+    SessionStopped, SessionStoppedArgs, StopReason, StreamMetadata, StreamMetadataArgs,
+};
+use flatbuffers::FlatBufferBuilder;
+use powerdna::session;
+use powerdna::{SensorData, StreamEvent};
+
+/// Sent as the `schema-version` header. Bump whenever `dataframe.fbs` changes incompatibly.
+pub const SCHEMA_VERSION: &str = "2";
+pub const CONTENT_TYPE: &str = "application/x-flatbuffers";
+
+/// Name of the `Event` union member an event is serialised as.
+pub fn event_type(event: &StreamEvent) -> &'static str {
+    match event {
+        StreamEvent::Buzzer { .. } => "BuzzerEvent",
+        StreamEvent::Metadata { .. } => "StreamMetadata",
+        StreamEvent::SessionStarted { .. } => "SessionStarted",
+        StreamEvent::SessionStopped { .. } => "SessionStopped",
+        StreamEvent::DataGap { .. } => "DataGap",
+    }
+}
+
+/// Serialises a frame of samples as a `DaiquiriData` flatbuffer.
+pub fn sensor_data(sensor_data: &SensorData) -> Vec<u8> {
+    let mut builder = FlatBufferBuilder::new();
+    let timestamps = Some(builder.create_vector(sensor_data.timestamps.as_slice()));
+    let frame = Some(builder.create_vector(sensor_data.data.as_slice()));
+    let ticks = Some(builder.create_vector(sensor_data.ticks.as_slice()));
+    let unix_ns = Some(builder.create_vector(sensor_data.unix_ns.as_slice()));
+    let session = Some(builder.create_string(&sensor_data.session));
+    let sensor_frame = SensorFrame::create(
+        &mut builder,
+        &SensorFrameArgs {
+            channels: sensor_data.channels as u8,
+            samples: sensor_data.samples() as u16,
+            timestamps,
+            frame,
+            ticks,
+            unix_ns,
+            session,
+            sequence: sensor_data.sequence,
+        },
+    );
+    let data = DaiquiriData::create(
+        &mut builder,
+        &DaiquiriDataArgs {
+            event_type: Event::SensorFrame,
+            event: Some(sensor_frame.as_union_value()),
+        },
+    );
+    builder.finish(data, None);
+    builder.finished_data().to_vec()
+}
+
+/// Serialises a stream event as a `DaiquiriData` flatbuffer.
+pub fn event(event: &StreamEvent) -> Vec<u8> {
+    let mut builder = FlatBufferBuilder::new();
+    let (event_type, event) = match event {
+        StreamEvent::Buzzer {
+            timestamp,
+            ticks,
+            unix_ns,
+        } => {
+            let buzzer_event = BuzzerEvent::create(
+                &mut builder,
+                &BuzzerEventArgs {
+                    timestamp: *timestamp,
+                    ticks: *ticks,
+                    unix_ns: *unix_ns,
+                },
+            );
+            (Event::BuzzerEvent, buzzer_event.as_union_value())
+        }
+        StreamEvent::Metadata { freq, channels } => {
+            let channels: Vec<_> = channels
+                .iter()
+                .map(|channel| {
+                    let name = Some(builder.create_string(&channel.name));
+                    let unit = Some(builder.create_string(&channel.unit));
+                    ChannelInfo::create(
+                        &mut builder,
+                        &ChannelInfoArgs {
+                            name,
+                            device: channel.device,
+                            channel: channel.channel,
+                            gain: channel.gain,
+                            unit,
+                        },
+                    )
+                })
+                .collect();
+            let channels = Some(builder.create_vector(&channels));
+            let metadata = StreamMetadata::create(
+                &mut builder,
+                &StreamMetadataArgs {
+                    freq: *freq,
+                    channels,
+                },
+            );
+            (Event::StreamMetadata, metadata.as_union_value())
+        }
+        StreamEvent::SessionStarted {
+            id,
+            label,
+            metadata,
+            unix_ns,
+        } => {
+            let id = Some(builder.create_string(id));
+            let label = label.as_deref().map(|label| builder.create_string(label));
+            let metadata = metadata
+                .as_ref()
+                .map(|metadata| builder.create_string(&metadata.to_string()));
+            let started = SessionStarted::create(
+                &mut builder,
+                &SessionStartedArgs {
+                    id,
+                    label,
+                    metadata,
+                    unix_ns: *unix_ns,
+                },
+            );
+            (Event::SessionStarted, started.as_union_value())
+        }
+        StreamEvent::SessionStopped {
+            id,
+            unix_ns,
+            frames,
+            reason,
+        } => {
+            let id = Some(builder.create_string(id));
+            let stopped = SessionStopped::create(
+                &mut builder,
+                &SessionStoppedArgs {
+                    id,
+                    unix_ns: *unix_ns,
+                    frames: *frames,
+                    reason: match reason {
+                        session::StopReason::Requested => StopReason::Requested,
+                        session::StopReason::Faulted => StopReason::Faulted,
+                        session::StopReason::Completed => StopReason::Completed,
+                    },
+                },
+            );
+            (Event::SessionStopped, stopped.as_union_value())
+        }
+        StreamEvent::DataGap {
+            sequence,
+            from_ticks,
+            from_unix_ns,
+            to_ticks,
+            to_unix_ns,
+            missing_scans,
+        } => {
+            let gap = DataGap::create(
+                &mut builder,
+                &DataGapArgs {
+                    sequence: *sequence,
+                    from_ticks: *from_ticks,
+                    from_unix_ns: *from_unix_ns,
+                    to_ticks: *to_ticks,
+                    to_unix_ns: *to_unix_ns,
+                    missing_scans: *missing_scans,
+                },
+            );
+            (Event::DataGap, gap.as_union_value())
+        }
+    };
+    let data = DaiquiriData::create(
+        &mut builder,
+        &DaiquiriDataArgs {
+            event_type,
+            event: Some(event),
+        },
+    );
+    builder.finish(data, None);
+    builder.finished_data().to_vec()
+}
@@ -0,0 +1,366 @@
+use crate::bootstrap::ConfigError;
+use powerdna::config::ConfigProblem;
+use powerdna::{DaqError, PowerDnaError};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::error::Error;
+use std::io;
+use tracing::error;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::{Rejection, Reply};
+
+#[derive(Debug)]
+pub enum ApiError {
+    UnknownStream(String),
+    UnknownSchedule { stream: String, id: u64 },
+    InvalidRequest(String),
+    InvalidConfig(Vec<ConfigProblem>),
+    PersistError(io::Error),
+    ConfigFile(ConfigError),
+    Daq(DaqError),
+}
+
+impl Reject for ApiError {}
+
+impl From<DaqError> for ApiError {
+    fn from(err: DaqError) -> Self {
+        ApiError::Daq(err)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: u16,
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    powerdna: Option<PowerDnaErrorResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    problems: Option<Vec<ConfigProblem>>,
+}
+
+#[derive(Serialize)]
+struct PowerDnaErrorResponse {
+    name: String,
+    code: i32,
+    description: String,
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::UnknownStream(_) => StatusCode::NOT_FOUND,
+            ApiError::UnknownSchedule { .. } => StatusCode::NOT_FOUND,
+            ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::InvalidConfig(_) => StatusCode::BAD_REQUEST,
+            ApiError::PersistError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::ConfigFile(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Daq(DaqError::StreamStateError) => StatusCode::CONFLICT,
+            ApiError::Daq(DaqError::PowerDnaError { source }) => powerdna_status(source),
+            ApiError::Daq(DaqError::HardwareMismatchError { .. }) => StatusCode::BAD_REQUEST,
+            ApiError::Daq(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ApiError::UnknownStream(_) => "UnknownStream",
+            ApiError::UnknownSchedule { .. } => "UnknownSchedule",
+            ApiError::InvalidRequest(_) => "InvalidRequest",
+            ApiError::InvalidConfig(_) => "InvalidConfig",
+            ApiError::PersistError(_) => "PersistError",
+            ApiError::ConfigFile(_) => "ConfigError",
+            ApiError::Daq(DaqError::BufferError) => "BufferError",
+            ApiError::Daq(DaqError::PowerDnaError { .. }) => "PowerDnaError",
+            ApiError::Daq(DaqError::StreamStateError) => "StreamStateError",
+            ApiError::Daq(DaqError::ChannelConfigError) => "ChannelConfigError",
+            ApiError::Daq(DaqError::GainConfigError) => "GainConfigError",
+            ApiError::Daq(DaqError::TimestampDecodeError) => "TimestampDecodeError",
+            ApiError::Daq(DaqError::RecordingError(_)) => "RecordingError",
+            ApiError::Daq(DaqError::HardwareMismatchError { .. }) => "HardwareMismatchError",
+        }
+    }
+
+    fn response(&self) -> ErrorResponse {
+        let problems = match self {
+            ApiError::InvalidConfig(problems) => Some(problems.clone()),
+            ApiError::Daq(DaqError::HardwareMismatchError { problems }) => Some(problems.clone()),
+            _ => None,
+        };
+        let (message, powerdna) = match self {
+            ApiError::UnknownStream(name) => (format!("No stream named '{}'.", name), None),
+            ApiError::UnknownSchedule { stream, id } => {
+                (format!("No schedule {} for stream '{}'.", id, stream), None)
+            }
+            ApiError::InvalidRequest(message) => (message.clone(), None),
+            ApiError::InvalidConfig(_) => (String::from("Invalid stream config."), None),
+            ApiError::PersistError(err) => (
+                format!(
+                    "The streams were changed but couldn't be saved to the config file: {}",
+                    err
+                ),
+                None,
+            ),
+            ApiError::ConfigFile(err) => (
+                match err.source() {
+                    Some(source) => format!("{} {}", err, source),
+                    None => err.to_string(),
+                },
+                None,
+            ),
+            ApiError::Daq(err) => {
+                let powerdna = match err {
+                    DaqError::PowerDnaError { source } => Some(PowerDnaErrorResponse {
+                        name: format!("{:?}", source),
+                        code: source.code(),
+                        description: source.translate(),
+                    }),
+                    _ => None,
+                };
+                (err.to_string(), powerdna)
+            }
+        };
+        ErrorResponse {
+            code: self.status().as_u16(),
+            error: self.name(),
+            message,
+            powerdna,
+            problems,
+        }
+    }
+}
+
+/// Communication failures with the IOM are reported as a bad gateway, and a device that is
+/// temporarily unable to serve the request as unavailable.
+fn powerdna_status(err: &PowerDnaError) -> StatusCode {
+    match err {
+        PowerDnaError::SocketError
+        | PowerDnaError::TimeoutError
+        | PowerDnaError::SendingError
+        | PowerDnaError::ReceivingError
+        | PowerDnaError::IomError
+        | PowerDnaError::PacketTooLong
+        | PowerDnaError::IllegalPacketSize
+        | PowerDnaError::WrongPacketCounter
+        | PowerDnaError::ProtocolMismatch
+        | PowerDnaError::CrcCheckFailed => StatusCode::BAD_GATEWAY,
+        PowerDnaError::DeviceBusy
+        | PowerDnaError::DeviceNotReady
+        | PowerDnaError::DeviceLocked
+        | PowerDnaError::DataNotAvailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let response = if let Some(api_error) = err.find::<ApiError>() {
+        api_error.response()
+    } else if err.is_not_found() {
+        ErrorResponse {
+            code: StatusCode::NOT_FOUND.as_u16(),
+            error: "NotFound",
+            message: String::from("Not found."),
+            powerdna: None,
+            problems: None,
+        }
+    } else if let Some(body_error) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        ErrorResponse {
+            code: StatusCode::BAD_REQUEST.as_u16(),
+            error: "InvalidRequest",
+            message: body_error.to_string(),
+            powerdna: None,
+            problems: None,
+        }
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        ErrorResponse {
+            code: StatusCode::METHOD_NOT_ALLOWED.as_u16(),
+            error: "MethodNotAllowed",
+            message: String::from("Method not allowed."),
+            powerdna: None,
+            problems: None,
+        }
+    } else {
+        error!(rejection = ?err, "Unhandled rejection");
+        ErrorResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            error: "InternalError",
+            message: String::from("Internal error."),
+            powerdna: None,
+            problems: None,
+        }
+    };
+
+    let status = StatusCode::from_u16(response.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        status,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn problems() -> Vec<ConfigProblem> {
+        vec![ConfigProblem {
+            path: String::from("rig.freq"),
+            message: String::from("must be positive"),
+        }]
+    }
+
+    /// The status and JSON body that `handle_rejection` replies with.
+    async fn reply(rejection: Rejection) -> (StatusCode, Value) {
+        let response = handle_rejection(rejection).await.unwrap().into_response();
+        let status = response.status();
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn rejected(err: ApiError) -> (StatusCode, Value) {
+        reply(warp::reject::custom(err)).await
+    }
+
+    #[tokio::test]
+    async fn reports_each_error() {
+        let cases = [
+            (
+                ApiError::UnknownStream(String::from("rig")),
+                StatusCode::NOT_FOUND,
+                "UnknownStream",
+                "No stream named 'rig'.",
+            ),
+            (
+                ApiError::UnknownSchedule {
+                    stream: String::from("rig"),
+                    id: 3,
+                },
+                StatusCode::NOT_FOUND,
+                "UnknownSchedule",
+                "No schedule 3 for stream 'rig'.",
+            ),
+            (
+                ApiError::InvalidRequest(String::from("Bad time.")),
+                StatusCode::BAD_REQUEST,
+                "InvalidRequest",
+                "Bad time.",
+            ),
+            (
+                ApiError::InvalidConfig(problems()),
+                StatusCode::BAD_REQUEST,
+                "InvalidConfig",
+                "Invalid stream config.",
+            ),
+            (
+                ApiError::PersistError(io::Error::other("disk full")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "PersistError",
+                "The streams were changed but couldn't be saved to the config file: disk full",
+            ),
+            (
+                ApiError::ConfigFile(ConfigError::FileError {
+                    source: io::Error::new(io::ErrorKind::NotFound, "no such file"),
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "ConfigError",
+                "Couldn't open config file. no such file",
+            ),
+            (
+                ApiError::Daq(DaqError::StreamStateError),
+                StatusCode::CONFLICT,
+                "StreamStateError",
+                "Invalid state for this action.",
+            ),
+            (
+                ApiError::Daq(DaqError::HardwareMismatchError {
+                    problems: problems(),
+                }),
+                StatusCode::BAD_REQUEST,
+                "HardwareMismatchError",
+                "Configured boards don't match the IOM: rig.freq: must be positive",
+            ),
+            (
+                ApiError::Daq(DaqError::BufferError),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "BufferError",
+                "Failed to allocate inbound data buffer.",
+            ),
+        ];
+        for (err, status, name, message) in cases {
+            let (actual, body) = rejected(err).await;
+            assert_eq!(actual, status, "{}", name);
+            assert_eq!(body["code"], status.as_u16(), "{}", name);
+            assert_eq!(body["error"], name);
+            assert_eq!(body["message"], message, "{}", name);
+            assert!(body.get("powerdna").is_none(), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_config_problems() {
+        for err in [
+            ApiError::InvalidConfig(problems()),
+            ApiError::Daq(DaqError::HardwareMismatchError {
+                problems: problems(),
+            }),
+        ] {
+            let (_, body) = rejected(err).await;
+            assert_eq!(
+                body["problems"],
+                serde_json::json!([{ "path": "rig.freq", "message": "must be positive" }])
+            );
+        }
+        let (_, body) = rejected(ApiError::Daq(DaqError::BufferError)).await;
+        assert!(body.get("problems").is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_powerdna_errors_by_kind() {
+        let cases = [
+            (PowerDnaError::TimeoutError, StatusCode::BAD_GATEWAY),
+            (PowerDnaError::DeviceBusy, StatusCode::SERVICE_UNAVAILABLE),
+            (
+                PowerDnaError::BadParameter,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (source, status) in cases {
+            let (actual, body) = rejected(ApiError::Daq(DaqError::from(source))).await;
+            assert_eq!(actual, status);
+            assert_eq!(body["error"], "PowerDnaError");
+            assert_eq!(body["powerdna"]["name"], format!("{:?}", source));
+            assert_eq!(body["powerdna"]["code"], source.code());
+            assert!(body["powerdna"]["description"].is_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_rejections_from_warp() {
+        let (status, body) = reply(warp::reject::not_found()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "NotFound");
+
+        let filter = warp::body::json::<u32>();
+        let rejection = warp::test::request()
+            .body("not json")
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        let (status, body) = reply(rejection).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "InvalidRequest");
+
+        let filter = warp::post();
+        let rejection = warp::test::request()
+            .method("GET")
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        let (status, body) = reply(rejection).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(body["error"], "MethodNotAllowed");
+    }
+}
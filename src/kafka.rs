@@ -0,0 +1,475 @@
+use crate::bootstrap::ConfigError;
+use crate::encode;
+use crate::spool::SpooledMessage;
+use powerdna::config::{KafkaConfig, PartitionStrategy, StreamConfig};
+use rdkafka::ClientConfig;
+use std::collections::BTreeMap;
+use std::env;
+
+/// Environment variables with this prefix are passed to the producer as librdkafka properties,
+/// e.g. `KAFKA_COMPRESSION_TYPE=lz4` sets `compression.type`.
+const ENV_PREFIX: &str = "KAFKA_";
+
+/// librdkafka aliases, and the property each is an alias of. `brokers` comes from `KAFKA_BROKERS`.
+const ALIASES: [(&str, &str); 4] = [
+    ("request.required.acks", "acks"),
+    ("compression.codec", "compression.type"),
+    ("metadata.broker.list", "bootstrap.servers"),
+    ("brokers", "bootstrap.servers"),
+];
+
+const DEFAULTS: [(&str, &str); 2] = [
+    ("bootstrap.servers", "host.docker.internal:19092"),
+    ("message.timeout.ms", "5000"),
+];
+
+/// Builds the producer config from the defaults overridden by `KAFKA_*` environment variables.
+/// `KAFKA_BROKERS` is accepted as a shorthand for `KAFKA_BOOTSTRAP_SERVERS`.
+pub fn producer_config() -> Result<ClientConfig, ConfigError> {
+    let mut config = ClientConfig::new();
+    for (key, value) in &env_properties(env::vars())? {
+        config.set(key, value);
+    }
+    Ok(config)
+}
+
+/// The producer properties set by `vars`, filled in with the defaults and checked.
+fn env_properties(
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<BTreeMap<String, String>, ConfigError> {
+    let mut properties = BTreeMap::new();
+    for (key, value) in vars {
+        let name = match key.strip_prefix(ENV_PREFIX) {
+            Some(name) if !is_service_link(name) => name,
+            _ => continue,
+        };
+        properties.insert(name.to_lowercase().replace('_', "."), value);
+    }
+
+    normalise_aliases(&mut properties)?;
+    for (key, value) in DEFAULTS {
+        properties
+            .entry(key.to_string())
+            .or_insert_with(|| value.to_string());
+    }
+    validate(&properties)?;
+    Ok(properties)
+}
+
+/// Whether a variable is one of those that Docker links and Kubernetes inject for a service named
+/// `kafka`, e.g. `KAFKA_PORT` or `KAFKA_SERVICE_HOST`, rather than a librdkafka property.
+fn is_service_link(name: &str) -> bool {
+    name == "PORT" || name.starts_with("PORT_") || name.starts_with("SERVICE_")
+}
+
+fn invalid(property: &str, message: &str) -> ConfigError {
+    ConfigError::InvalidKafkaSetting {
+        property: property.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// Renames aliased properties to the names `validate` checks, so that a setting can't slip past it
+/// under its alias.
+fn normalise_aliases(properties: &mut BTreeMap<String, String>) -> Result<(), ConfigError> {
+    for (alias, property) in ALIASES {
+        if let Some(value) = properties.remove(alias) {
+            if properties.contains_key(property) {
+                return Err(invalid(
+                    property,
+                    &format!("can't be combined with its alias {}", alias),
+                ));
+            }
+            properties.insert(property.to_string(), value);
+        }
+    }
+    Ok(())
+}
+
+/// Catches the common mistakes early; librdkafka rejects unknown properties itself when the
+/// producer is created.
+fn validate(properties: &BTreeMap<String, String>) -> Result<(), ConfigError> {
+    let get = |key: &str| properties.get(key).map(|value| value.trim());
+
+    match get("bootstrap.servers") {
+        None | Some("") => return Err(invalid("bootstrap.servers", "no brokers configured")),
+        Some(brokers) => {
+            for broker in brokers.split(',').map(str::trim) {
+                let valid = match broker.rsplit_once(':') {
+                    Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+                    None => false,
+                };
+                if !valid {
+                    return Err(invalid(
+                        "bootstrap.servers",
+                        &format!("expected host:port, got '{}'", broker),
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(acks) = get("acks") {
+        let valid = acks == "all" || matches!(acks.parse::<i32>(), Ok(val) if val >= -1);
+        if !valid {
+            return Err(invalid("acks", "expected all, -1, 0 or a positive count"));
+        }
+    }
+
+    if let Some(compression) = get("compression.type") {
+        if !["none", "gzip", "snappy", "lz4", "zstd"].contains(&compression) {
+            return Err(invalid(
+                "compression.type",
+                "expected none, gzip, snappy, lz4 or zstd",
+            ));
+        }
+    }
+
+    for key in ["linger.ms", "queue.buffering.max.ms"] {
+        if let Some(value) = get(key) {
+            if !matches!(value.parse::<f64>(), Ok(val) if val >= 0.0) {
+                return Err(invalid(
+                    key,
+                    "expected a non-negative number of milliseconds",
+                ));
+            }
+        }
+    }
+
+    if let Some(value) = get("message.timeout.ms") {
+        if value.parse::<u32>().is_err() {
+            return Err(invalid(
+                "message.timeout.ms",
+                "expected a whole number of milliseconds",
+            ));
+        }
+    }
+
+    let idempotent = match get("enable.idempotence") {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => return Err(invalid("enable.idempotence", "expected true or false")),
+    };
+    if idempotent && !matches!(get("acks"), None | Some("all") | Some("-1")) {
+        return Err(invalid(
+            "acks",
+            "must be all when enable.idempotence is true",
+        ));
+    }
+
+    let protocol = get("security.protocol").map(str::to_lowercase);
+    if let Some(protocol) = &protocol {
+        if !["plaintext", "ssl", "sasl_plaintext", "sasl_ssl"].contains(&protocol.as_str()) {
+            return Err(invalid(
+                "security.protocol",
+                "expected plaintext, ssl, sasl_plaintext or sasl_ssl",
+            ));
+        }
+    }
+    let uses_sasl = properties.keys().any(|key| key.starts_with("sasl."));
+    if uses_sasl
+        && !matches!(
+            protocol.as_deref(),
+            Some("sasl_plaintext") | Some("sasl_ssl")
+        )
+    {
+        return Err(invalid(
+            "security.protocol",
+            "must be sasl_plaintext or sasl_ssl when SASL settings are given",
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MessageKind {
+    Sensor,
+    Event,
+}
+
+/// Addresses a stream's messages according to its `kafka` config.
+#[derive(Clone)]
+pub struct Route {
+    stream: String,
+    config: KafkaConfig,
+    /// Device numbers of the input boards, joined with `+`.
+    inputs: String,
+    /// Device numbers of the output boards, joined with `+`.
+    outputs: String,
+}
+
+fn devices(devices: impl Iterator<Item = u8>) -> String {
+    devices
+        .map(|device| device.to_string())
+        .collect::<Vec<String>>()
+        .join("+")
+}
+
+impl Route {
+    pub fn new(stream: &str, config: &StreamConfig) -> Self {
+        Route {
+            stream: stream.to_string(),
+            config: config.kafka.clone(),
+            inputs: devices(config.boards.iter().map(|board| board.device)),
+            outputs: devices(config.outputs.iter().map(|output| output.device)),
+        }
+    }
+
+    /// `event_type` names the payload's `Event` union member, and `session` the session it
+    /// belongs to.
+    pub fn message(
+        &self,
+        kind: MessageKind,
+        event_type: &str,
+        session: &str,
+        payload: Vec<u8>,
+    ) -> SpooledMessage {
+        let (kind, topic) = match kind {
+            MessageKind::Sensor => ("sensor", &self.config.sensor_topic),
+            MessageKind::Event => ("event", &self.config.event_topic),
+        };
+        // buzzer events come from the output boards, everything else from the input boards
+        let board = match event_type {
+            "BuzzerEvent" => &self.outputs,
+            _ => &self.inputs,
+        };
+        let key = match self.config.partition {
+            PartitionStrategy::Random => None,
+            _ => Some(
+                self.config
+                    .key
+                    .replace("{kind}", kind)
+                    .replace("{event}", event_type)
+                    .replace("{session}", session)
+                    .replace("{board}", board)
+                    .replace("{stream}", &self.stream),
+            ),
+        };
+        let partition = match self.config.partition {
+            PartitionStrategy::Fixed { partition } => Some(partition),
+            _ => None,
+        };
+        let mut headers = vec![
+            (
+                String::from("schema-version"),
+                String::from(encode::SCHEMA_VERSION),
+            ),
+            (
+                String::from("content-type"),
+                String::from(encode::CONTENT_TYPE),
+            ),
+            (String::from("stream"), self.stream.clone()),
+        ];
+        headers.extend(
+            self.config
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        SpooledMessage {
+            topic: topic.clone().unwrap_or_else(|| self.stream.clone()),
+            key,
+            partition,
+            headers,
+            payload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use powerdna::config::STANDARD_HEADERS;
+
+    fn properties(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        let mut properties: BTreeMap<String, String> = DEFAULTS
+            .iter()
+            .chain(pairs)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        normalise_aliases(&mut properties).unwrap();
+        properties
+    }
+
+    /// The property a config is rejected for, if any.
+    fn rejected(pairs: &[(&str, &str)]) -> Option<String> {
+        match validate(&properties(pairs)) {
+            Ok(_) => None,
+            Err(ConfigError::InvalidKafkaSetting { property, .. }) => Some(property),
+            Err(err) => panic!("unexpected error {}", err),
+        }
+    }
+
+    fn routed(kafka: serde_json::Value) -> Route {
+        let config: StreamConfig = serde_json::from_value(serde_json::json!({
+            "ip": "192.168.100.2",
+            "freq": 1000,
+            "frame_size": 100,
+            "boards": [
+                { "device": 0, "channels": [{ "id": 0, "gain": 1 }] },
+                { "device": 1, "channels": [{ "id": 0, "gain": 1 }] },
+            ],
+            "outputs": [{ "device": 3 }],
+            "kafka": kafka,
+        }))
+        .unwrap();
+        Route::new("rig", &config)
+    }
+
+    #[test]
+    fn fills_in_key_placeholders() {
+        let route =
+            routed(serde_json::json!({ "key": "{stream}/{kind}/{event}/{session}/{board}" }));
+        let message = route.message(MessageKind::Sensor, "SensorFrame", "abc", vec![1]);
+        assert_eq!(
+            message.key.as_deref(),
+            Some("rig/sensor/SensorFrame/abc/0+1")
+        );
+        let message = route.message(MessageKind::Event, "BuzzerEvent", "abc", vec![]);
+        assert_eq!(message.key.as_deref(), Some("rig/event/BuzzerEvent/abc/3"));
+    }
+
+    #[test]
+    fn addresses_messages_by_the_config() {
+        let message = routed(serde_json::json!({})).message(MessageKind::Event, "X", "abc", vec![]);
+        assert_eq!(message.topic, "rig");
+        assert_eq!(message.key.as_deref(), Some("rig"));
+        assert_eq!(message.partition, None);
+
+        let route = routed(serde_json::json!({
+            "sensor_topic": "frames",
+            "partition": { "strategy": "fixed", "partition": 2 },
+            "headers": { "site": "lab" },
+        }));
+        let message = route.message(MessageKind::Sensor, "SensorFrame", "abc", vec![1]);
+        assert_eq!(message.topic, "frames");
+        assert_eq!(message.partition, Some(2));
+        assert_eq!(message.payload, [1]);
+        let headers: Vec<&str> = message
+            .headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(
+            headers,
+            STANDARD_HEADERS
+                .iter()
+                .chain(&["site"])
+                .copied()
+                .collect::<Vec<_>>()
+        );
+
+        let route = routed(serde_json::json!({ "partition": { "strategy": "random" } }));
+        let message = route.message(MessageKind::Sensor, "SensorFrame", "abc", vec![]);
+        assert_eq!(message.key, None);
+    }
+
+    #[test]
+    fn accepts_the_defaults() {
+        assert_eq!(rejected(&[]), None);
+    }
+
+    #[test]
+    fn checks_brokers() {
+        assert_eq!(
+            rejected(&[("bootstrap.servers", "kafka:9092, other:9093")]),
+            None
+        );
+        for brokers in ["", "kafka", ":9092", "kafka:port"] {
+            assert_eq!(
+                rejected(&[("bootstrap.servers", brokers)]).as_deref(),
+                Some("bootstrap.servers")
+            );
+        }
+    }
+
+    #[test]
+    fn checks_acks_under_either_name() {
+        for name in ["acks", "request.required.acks"] {
+            assert_eq!(rejected(&[(name, "all")]), None);
+            assert_eq!(rejected(&[(name, "1")]), None);
+            assert_eq!(rejected(&[(name, "some")]).as_deref(), Some("acks"));
+        }
+    }
+
+    #[test]
+    fn requires_all_acks_for_idempotence_under_either_name() {
+        for name in ["acks", "request.required.acks"] {
+            let pairs = [("enable.idempotence", "true"), (name, "1")];
+            assert_eq!(rejected(&pairs).as_deref(), Some("acks"));
+            let pairs = [("enable.idempotence", "true"), (name, "-1")];
+            assert_eq!(rejected(&pairs), None);
+        }
+    }
+
+    #[test]
+    fn rejects_a_property_given_under_both_names() {
+        let mut properties = properties(&[("acks", "all")]);
+        properties.insert(String::from("request.required.acks"), String::from("1"));
+        assert!(normalise_aliases(&mut properties).is_err());
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn ignores_variables_set_for_a_kafka_service() {
+        let properties = env_properties(vars(&[
+            ("KAFKA_PORT", "tcp://10.0.0.1:9092"),
+            ("KAFKA_PORT_9092_TCP_ADDR", "10.0.0.1"),
+            ("KAFKA_SERVICE_HOST", "10.0.0.1"),
+            ("KAFKA_SERVICE_PORT", "9092"),
+            ("KAFKA_LINGER_MS", "20"),
+            ("PATH", "/usr/bin"),
+        ]))
+        .unwrap();
+        let keys: Vec<&str> = properties.keys().map(String::as_str).collect();
+        assert_eq!(
+            keys,
+            ["bootstrap.servers", "linger.ms", "message.timeout.ms"]
+        );
+    }
+
+    #[test]
+    fn takes_brokers_as_bootstrap_servers() {
+        let properties = env_properties(vars(&[("KAFKA_BROKERS", "kafka:9092")])).unwrap();
+        assert_eq!(properties["bootstrap.servers"], "kafka:9092");
+        assert!(!properties.contains_key("brokers"));
+
+        let both = vars(&[
+            ("KAFKA_BROKERS", "kafka:9092"),
+            ("KAFKA_BOOTSTRAP_SERVERS", "other:9092"),
+        ]);
+        match env_properties(both) {
+            Err(ConfigError::InvalidKafkaSetting { property, .. }) => {
+                assert_eq!(property, "bootstrap.servers")
+            }
+            _ => panic!("brokers were accepted under both names"),
+        }
+    }
+
+    #[test]
+    fn checks_compression_under_either_name() {
+        assert_eq!(rejected(&[("compression.codec", "lz4")]), None);
+        assert_eq!(
+            rejected(&[("compression.codec", "brotli")]).as_deref(),
+            Some("compression.type")
+        );
+    }
+
+    #[test]
+    fn requires_a_sasl_protocol_for_sasl_settings() {
+        let pairs = [("sasl.mechanism", "PLAIN")];
+        assert_eq!(rejected(&pairs).as_deref(), Some("security.protocol"));
+        let pairs = [
+            ("sasl.mechanism", "PLAIN"),
+            ("security.protocol", "SASL_SSL"),
+        ];
+        assert_eq!(rejected(&pairs), None);
+    }
+}
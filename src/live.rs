@@ -0,0 +1,561 @@
+use crate::encode;
+use crate::errors::ApiError;
+use futures::{SinkExt, StreamExt};
+use powerdna::session::StopReason;
+use powerdna::{ChannelMetadata, EventData, SensorData, StreamEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, warn};
+use warp::ws::{Message, WebSocket};
+
+/// Messages buffered per stream and client before a slow client starts missing frames.
+const CAPACITY: usize = 64;
+
+const JSON_PROTOCOL: &str = "daiquiri.json";
+const FLATBUFFERS_PROTOCOL: &str = "daiquiri.flatbuffers";
+
+/// Everything published to Kafka, shared with the live WebSocket clients.
+#[derive(Clone)]
+pub enum LiveData {
+    Frame(Arc<SensorData>),
+    Event(Arc<EventData>),
+}
+
+/// Each stream's live channel, by stream name. A stream gets a channel of its own whenever it is
+/// created or replaced, so that clients never see frames laid out differently from the channels
+/// they subscribed to.
+#[derive(Clone, Default)]
+pub struct LiveStreams {
+    channels: Arc<Mutex<Channels>>,
+}
+
+#[derive(Default)]
+struct Channels {
+    next_id: u64,
+    senders: HashMap<String, (u64, broadcast::Sender<LiveData>)>,
+}
+
+impl LiveStreams {
+    /// Opens a new channel for the stream, replacing any it had before. Clients of the old channel
+    /// are disconnected once its `LiveChannel` has been dropped.
+    pub fn open(&self, stream: &str) -> LiveChannel {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        let mut channels = self.channels.lock().unwrap();
+        channels.next_id += 1;
+        let id = channels.next_id;
+        channels
+            .senders
+            .insert(stream.to_string(), (id, tx.clone()));
+        LiveChannel {
+            id,
+            stream: stream.to_string(),
+            tx,
+            streams: self.clone(),
+        }
+    }
+
+    pub fn subscribe(&self, stream: &str) -> Option<broadcast::Receiver<LiveData>> {
+        let channels = self.channels.lock().unwrap();
+        channels.senders.get(stream).map(|(_, tx)| tx.subscribe())
+    }
+}
+
+/// The sending end of a stream's live channel, held by its publisher tasks. The channel is closed
+/// when this is dropped, which ends the connections watching it.
+pub struct LiveChannel {
+    id: u64,
+    stream: String,
+    tx: broadcast::Sender<LiveData>,
+    streams: LiveStreams,
+}
+
+impl LiveChannel {
+    pub fn send(&self, data: LiveData) {
+        // an error only means no one is watching
+        let _ = self.tx.send(data);
+    }
+}
+
+impl Drop for LiveChannel {
+    fn drop(&mut self) {
+        let mut channels = self.streams.channels.lock().unwrap();
+        // the stream may have been replaced with a new channel already
+        if matches!(channels.senders.get(&self.stream), Some((id, _)) if *id == self.id) {
+            channels.senders.remove(&self.stream);
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Flatbuffers,
+}
+
+impl Format {
+    fn protocol(&self) -> &'static str {
+        match self {
+            Format::Json => JSON_PROTOCOL,
+            Format::Flatbuffers => FLATBUFFERS_PROTOCOL,
+        }
+    }
+}
+
+/// Query string of `GET /streams/:name/live`.
+#[derive(Deserialize)]
+pub struct LiveQuery {
+    format: Option<Format>,
+    /// Comma-separated channel names. All channels are sent if omitted.
+    channels: Option<String>,
+    /// Only every nth scan is sent.
+    decimate: Option<usize>,
+}
+
+/// Picks the format from the `format` query parameter, falling back to the first supported
+/// `Sec-WebSocket-Protocol` offered by the client. Returns the format and the protocol to accept,
+/// if the client offered any.
+pub fn negotiate(
+    query: &LiveQuery,
+    protocols: Option<&str>,
+) -> Result<(Format, Option<&'static str>), ApiError> {
+    let offered: Vec<&str> = protocols
+        .map(|protocols| protocols.split(',').map(str::trim).collect())
+        .unwrap_or_default();
+    let supported = offered.iter().find_map(|protocol| match *protocol {
+        JSON_PROTOCOL => Some(Format::Json),
+        FLATBUFFERS_PROTOCOL => Some(Format::Flatbuffers),
+        _ => None,
+    });
+    if !offered.is_empty() && supported.is_none() {
+        return Err(ApiError::InvalidRequest(format!(
+            "Expected the {} or {} subprotocol.",
+            JSON_PROTOCOL, FLATBUFFERS_PROTOCOL
+        )));
+    }
+    let format = query.format.or(supported).unwrap_or(Format::Json);
+    match offered.is_empty() {
+        true => Ok((format, None)),
+        false if offered.contains(&format.protocol()) => Ok((format, Some(format.protocol()))),
+        false => Err(ApiError::InvalidRequest(format!(
+            "The {} subprotocol was not offered.",
+            format.protocol()
+        ))),
+    }
+}
+
+/// A client's view of a stream: which channels it wants, in what format, and how often.
+pub struct Subscription {
+    stream: String,
+    format: Format,
+    channels: Vec<usize>,
+    names: Vec<String>,
+    decimate: usize,
+    /// Position of the next scan within the decimation period, carried across frames.
+    phase: usize,
+}
+
+impl Subscription {
+    pub fn new(
+        stream: String,
+        format: Format,
+        query: &LiveQuery,
+        metadata: &[ChannelMetadata],
+    ) -> Result<Self, ApiError> {
+        let channels = match &query.channels {
+            None => (0..metadata.len()).collect(),
+            Some(names) => names
+                .split(',')
+                .map(str::trim)
+                .map(|name| {
+                    metadata
+                        .iter()
+                        .position(|channel| channel.name == name)
+                        .ok_or_else(|| {
+                            ApiError::InvalidRequest(format!("No channel named '{}'.", name))
+                        })
+                })
+                .collect::<Result<Vec<usize>, ApiError>>()?,
+        };
+        let decimate = match query.decimate {
+            Some(0) => {
+                return Err(ApiError::InvalidRequest(String::from(
+                    "decimate must be greater than 0.",
+                )))
+            }
+            Some(decimate) => decimate,
+            None => 1,
+        };
+        Ok(Subscription {
+            stream,
+            format,
+            names: channels
+                .iter()
+                .map(|&index| metadata[index].name.clone())
+                .collect(),
+            channels,
+            decimate,
+            phase: 0,
+        })
+    }
+
+    /// Whether the frame has every channel the client subscribed to.
+    fn fits(&self, sensor_data: &SensorData) -> bool {
+        self.channels
+            .iter()
+            .all(|&channel| channel < sensor_data.channels)
+    }
+
+    /// Cuts a frame down to the selected channels and scans.
+    fn select(&mut self, sensor_data: &SensorData) -> Option<SensorData> {
+        let samples = sensor_data.samples();
+        let scans: Vec<usize> = (0..samples)
+            .filter(|scan| (self.phase + scan).is_multiple_of(self.decimate))
+            .collect();
+        self.phase = (self.phase + samples) % self.decimate;
+        if scans.is_empty() {
+            return None;
+        }
+
+        let data = scans
+            .iter()
+            .flat_map(|&scan| {
+                let row = sensor_data
+                    .data
+                    .get(scan * sensor_data.channels..(scan + 1) * sensor_data.channels)
+                    .unwrap_or_default();
+                self.channels
+                    .iter()
+                    .filter_map(move |&channel| row.get(channel).copied())
+            })
+            .collect();
+        Some(SensorData {
+            stream: sensor_data.stream.clone(),
+            session: sensor_data.session.clone(),
+            sequence: sensor_data.sequence,
+            channels: self.channels.len(),
+            data,
+            timestamps: scans.iter().map(|&i| sensor_data.timestamps[i]).collect(),
+            ticks: scans.iter().map(|&i| sensor_data.ticks[i]).collect(),
+            unix_ns: scans.iter().map(|&i| sensor_data.unix_ns[i]).collect(),
+        })
+    }
+
+    fn message(&mut self, data: &LiveData) -> Option<Message> {
+        match data {
+            LiveData::Frame(sensor_data) if sensor_data.stream == self.stream => {
+                let frame = self.select(sensor_data)?;
+                Some(match self.format {
+                    Format::Flatbuffers => Message::binary(encode::sensor_data(&frame)),
+                    Format::Json => json(&JsonMessage::SensorFrame {
+                        session: &frame.session,
+                        sequence: frame.sequence,
+                        channels: &self.names,
+                        samples: frame.samples(),
+                        timestamps: &frame.timestamps,
+                        ticks: &frame.ticks,
+                        unix_ns: &frame.unix_ns,
+                        frame: &frame.data,
+                    })?,
+                })
+            }
+            LiveData::Event(event) if event.stream == self.stream => match &event.event {
+                StreamEvent::Buzzer {
+                    timestamp,
+                    ticks,
+                    unix_ns,
+                } => Some(match self.format {
+                    Format::Flatbuffers => Message::binary(encode::event(&event.event)),
+                    Format::Json => json(&JsonMessage::BuzzerEvent {
+                        timestamp: *timestamp,
+                        ticks: *ticks,
+                        unix_ns: *unix_ns,
+                    })?,
+                }),
+                StreamEvent::SessionStarted {
+                    id,
+                    label,
+                    metadata,
+                    unix_ns,
+                } => Some(match self.format {
+                    Format::Flatbuffers => Message::binary(encode::event(&event.event)),
+                    Format::Json => json(&JsonMessage::SessionStarted {
+                        id,
+                        label: label.as_deref(),
+                        metadata: metadata.as_ref(),
+                        unix_ns: *unix_ns,
+                    })?,
+                }),
+                StreamEvent::SessionStopped {
+                    id,
+                    unix_ns,
+                    frames,
+                    reason,
+                } => Some(match self.format {
+                    Format::Flatbuffers => Message::binary(encode::event(&event.event)),
+                    Format::Json => json(&JsonMessage::SessionStopped {
+                        id,
+                        unix_ns: *unix_ns,
+                        frames: *frames,
+                        reason: *reason,
+                    })?,
+                }),
+                StreamEvent::DataGap {
+                    sequence,
+                    from_ticks,
+                    from_unix_ns,
+                    to_ticks,
+                    to_unix_ns,
+                    missing_scans,
+                } => Some(match self.format {
+                    Format::Flatbuffers => Message::binary(encode::event(&event.event)),
+                    Format::Json => json(&JsonMessage::DataGap {
+                        sequence: *sequence,
+                        from_ticks: *from_ticks,
+                        from_unix_ns: *from_unix_ns,
+                        to_ticks: *to_ticks,
+                        to_unix_ns: *to_unix_ns,
+                        missing_scans: *missing_scans,
+                    })?,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// JSON counterpart of the flatbuffers schema, tagged with the table name.
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum JsonMessage<'a> {
+    SensorFrame {
+        session: &'a str,
+        sequence: u64,
+        channels: &'a [String],
+        samples: usize,
+        timestamps: &'a [u32],
+        ticks: &'a [u64],
+        unix_ns: &'a [u64],
+        frame: &'a [f64],
+    },
+    BuzzerEvent {
+        timestamp: u32,
+        ticks: u64,
+        unix_ns: u64,
+    },
+    SessionStarted {
+        id: &'a str,
+        label: Option<&'a str>,
+        metadata: Option<&'a serde_json::Value>,
+        unix_ns: u64,
+    },
+    SessionStopped {
+        id: &'a str,
+        unix_ns: u64,
+        frames: u64,
+        reason: StopReason,
+    },
+    DataGap {
+        sequence: u64,
+        from_ticks: u64,
+        from_unix_ns: u64,
+        to_ticks: u64,
+        to_unix_ns: u64,
+        missing_scans: u64,
+    },
+}
+
+fn json(message: &JsonMessage) -> Option<Message> {
+    match serde_json::to_string(message) {
+        Ok(text) => Some(Message::text(text)),
+        Err(err) => {
+            error!(error = %err, "Failed to serialise live data");
+            None
+        }
+    }
+}
+
+/// Forwards live data to a client until either side goes away, or the stream is replaced. Anything
+/// the client sends is ignored.
+pub async fn serve(
+    socket: WebSocket,
+    mut subscription: Subscription,
+    mut rx: broadcast::Receiver<LiveData>,
+) {
+    let (mut tx, mut incoming) = socket.split();
+    loop {
+        tokio::select! {
+            message = incoming.next() => match message {
+                Some(Ok(message)) if !message.is_close() => continue,
+                _ => break,
+            },
+            data = rx.recv() => match data {
+                Ok(LiveData::Frame(frame)) if !subscription.fits(&frame) => {
+                    warn!(stream = %subscription.stream, "Stream layout changed, closing live client");
+                    break;
+                }
+                Ok(data) => {
+                    if let Some(message) = subscription.message(&data) {
+                        if tx.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => warn!(
+                    stream = %subscription.stream,
+                    skipped,
+                    "Live client fell behind"
+                ),
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+    let _ = tx.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(format: Option<Format>, channels: Option<&str>, decimate: Option<usize>) -> LiveQuery {
+        LiveQuery {
+            format,
+            channels: channels.map(String::from),
+            decimate,
+        }
+    }
+
+    fn metadata(names: &[&str]) -> Vec<ChannelMetadata> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| ChannelMetadata {
+                name: name.to_string(),
+                device: 0,
+                channel: i as u8,
+                gain: 1,
+                unit: String::from("V"),
+            })
+            .collect()
+    }
+
+    /// A frame of `samples` scans of `channels` channels, where each value is `scan * 10 + channel`.
+    fn frame(channels: usize, samples: usize) -> SensorData {
+        SensorData {
+            stream: String::from("s"),
+            session: String::from("session"),
+            sequence: 0,
+            channels,
+            data: (0..samples)
+                .flat_map(|scan| (0..channels).map(move |channel| (scan * 10 + channel) as f64))
+                .collect(),
+            timestamps: (0..samples as u32).collect(),
+            ticks: (0..samples as u64).collect(),
+            unix_ns: (0..samples as u64).collect(),
+        }
+    }
+
+    fn subscription(channels: Option<&str>, decimate: Option<usize>) -> Subscription {
+        Subscription::new(
+            String::from("s"),
+            Format::Json,
+            &query(None, channels, decimate),
+            &metadata(&["a", "b", "c"]),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn defaults_to_json_without_a_subprotocol() {
+        let (format, protocol) = negotiate(&query(None, None, None), None).unwrap();
+        assert!(format == Format::Json);
+        assert_eq!(protocol, None);
+    }
+
+    #[test]
+    fn picks_the_first_supported_subprotocol() {
+        let offered = "chat, daiquiri.flatbuffers, daiquiri.json";
+        let (format, protocol) = negotiate(&query(None, None, None), Some(offered)).unwrap();
+        assert!(format == Format::Flatbuffers);
+        assert_eq!(protocol, Some(FLATBUFFERS_PROTOCOL));
+    }
+
+    #[test]
+    fn prefers_the_format_parameter() {
+        let offered = "daiquiri.flatbuffers, daiquiri.json";
+        let (format, protocol) =
+            negotiate(&query(Some(Format::Json), None, None), Some(offered)).unwrap();
+        assert!(format == Format::Json);
+        assert_eq!(protocol, Some(JSON_PROTOCOL));
+    }
+
+    #[test]
+    fn rejects_unsupported_or_mismatched_subprotocols() {
+        assert!(negotiate(&query(None, None, None), Some("chat")).is_err());
+        let flatbuffers = query(Some(Format::Flatbuffers), None, None);
+        assert!(negotiate(&flatbuffers, Some(JSON_PROTOCOL)).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_channels_and_zero_decimation() {
+        let metadata = metadata(&["a"]);
+        let unknown = query(None, Some("a,x"), None);
+        assert!(Subscription::new(String::from("s"), Format::Json, &unknown, &metadata).is_err());
+        let zero = query(None, None, Some(0));
+        assert!(Subscription::new(String::from("s"), Format::Json, &zero, &metadata).is_err());
+    }
+
+    #[test]
+    fn selects_channels_in_the_order_asked_for() {
+        let mut subscription = subscription(Some("c, a"), None);
+        let selected = subscription.select(&frame(3, 2)).unwrap();
+        assert_eq!(selected.channels, 2);
+        assert_eq!(selected.data, [2.0, 0.0, 12.0, 10.0]);
+        assert_eq!(subscription.names, ["c", "a"]);
+    }
+
+    #[test]
+    fn decimates_across_frames() {
+        let mut subscription = subscription(Some("a"), Some(3));
+        let first = subscription.select(&frame(3, 4)).unwrap();
+        assert_eq!(first.timestamps, [0, 3]);
+        // the next frame carries on where the last one left off, 2 scans short of the period
+        let second = subscription.select(&frame(3, 4)).unwrap();
+        assert_eq!(second.timestamps, [2]);
+        assert_eq!(second.data, [20.0]);
+        assert!(subscription.select(&frame(3, 1)).is_none());
+    }
+
+    #[test]
+    fn notices_frames_with_fewer_channels() {
+        let mut subscription = subscription(Some("c"), None);
+        let narrower = frame(2, 2);
+        assert!(!subscription.fits(&narrower));
+        // selecting one anyway doesn't panic
+        assert_eq!(
+            subscription.select(&narrower).unwrap().data,
+            Vec::<f64>::new()
+        );
+        assert!(subscription.fits(&frame(3, 2)));
+    }
+
+    #[tokio::test]
+    async fn closes_a_replaced_streams_channel() {
+        let streams = LiveStreams::default();
+        let old = streams.open("s");
+        let mut old_rx = streams.subscribe("s").unwrap();
+        let new = streams.open("s");
+        let mut new_rx = streams.subscribe("s").unwrap();
+        drop(old);
+        assert!(matches!(old_rx.recv().await, Err(RecvError::Closed)));
+
+        new.send(LiveData::Frame(Arc::new(frame(1, 1))));
+        assert!(matches!(new_rx.recv().await, Ok(LiveData::Frame(_))));
+        drop(new);
+        assert!(streams.subscribe("s").is_none());
+    }
+}
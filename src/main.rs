@@ -1,12 +1,38 @@
-use bootstrap::initialise;
-use powerdna::SignalManager;
+use bootstrap::{initialise, ConfigError, Publisher, StreamFactory};
+use chrono::{DateTime, Utc};
+use errors::{handle_rejection, ApiError};
+use live::{LiveQuery, LiveStreams, Subscription};
+use powerdna::config::{validate, StreamConfig};
+use powerdna::session::{Session, StartOptions};
+use powerdna::{DaqError, SignalManager, StreamState};
+use scheduler::{ScheduleRequest, ScheduleStore};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::env;
+use std::panic;
 use std::sync::Arc;
+use supervisor::SupervisorConfig;
 use tokio::signal;
-use tokio::sync::Mutex;
-use warp::Filter;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task;
+use tracing::{error, info, info_span};
+use tracing_subscriber::fmt::format::{Format, Json, JsonFields};
+use tracing_subscriber::fmt::{MakeWriter, SubscriberBuilder};
+use tracing_subscriber::EnvFilter;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Reply};
 
 mod bootstrap;
+mod encode;
+mod errors;
+mod kafka;
+mod live;
+mod metrics;
+mod scheduler;
+mod spool;
+mod supervisor;
 
 #[allow(dead_code, unused_imports)]
 #[path = "../target/flatbuffers/dataframe_generated.rs"]
@@ -14,12 +40,133 @@ mod dataframe_generated;
 
 type SignalStore = Arc<Mutex<HashMap<String, SignalManager>>>;
 
+#[derive(Serialize)]
+struct SessionResponse {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    samples: Option<u64>,
+    started_at: DateTime<Utc>,
+}
+
+impl From<Session> for SessionResponse {
+    fn from(session: Session) -> Self {
+        SessionResponse {
+            id: session.id,
+            label: session.options.label,
+            metadata: session.options.metadata,
+            duration_secs: session.options.duration_secs,
+            samples: session.options.samples,
+            started_at: DateTime::<Utc>::from(session.started),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StreamResponse<'a> {
+    name: &'a str,
+    config: &'a StreamConfig,
+    state: StreamState,
+    running: bool,
+    /// Why the stream is faulted, if it is.
+    fault: Option<String>,
+    faulted_at: Option<DateTime<Utc>>,
+    session: Option<SessionResponse>,
+    actual_freq: Option<f32>,
+    started_at: Option<DateTime<Utc>>,
+    frames: u64,
+    packets_lost: u64,
+    muxer_queue: u64,
+    publisher_queue: u64,
+    frames_dropped: u64,
+    events_dropped: u64,
+    data_gaps: u64,
+}
+
+impl<'a> StreamResponse<'a> {
+    fn new(name: &'a str, manager: &'a SignalManager) -> Self {
+        let status = manager.status();
+        StreamResponse {
+            name,
+            config: manager.config(),
+            state: status.state,
+            running: status.running,
+            fault: status.fault.as_ref().map(|fault| fault.reason.clone()),
+            faulted_at: status
+                .fault
+                .as_ref()
+                .map(|fault| DateTime::<Utc>::from(fault.since)),
+            session: status.session.map(SessionResponse::from),
+            actual_freq: status.actual_freq,
+            started_at: status.started.map(DateTime::<Utc>::from),
+            frames: status.frames,
+            packets_lost: status.packets_lost,
+            muxer_queue: status.muxer_queue,
+            publisher_queue: status.publisher_queue,
+            frames_dropped: status.frames_dropped,
+            events_dropped: status.events_dropped,
+            data_gaps: status.data_gaps,
+        }
+    }
+}
+
+/// Logs go to stderr, filtered by `RUST_LOG` (`info` by default). Setting `LOG_FORMAT=json` writes
+/// one JSON object per line instead.
+fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => json_logs(filter, std::io::stderr).init(),
+        _ => tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr)
+            .init(),
+    }
+}
+
+/// Logs as JSON objects that carry the fields of the spans they were logged in, e.g. the stream.
+fn json_logs<W>(
+    filter: EnvFilter,
+    writer: W,
+) -> SubscriberBuilder<JsonFields, Format<Json>, EnvFilter, W>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+}
+
 #[tokio::main]
 async fn main() {
-    let signal_managers = initialise().expect("Failed to initialise DAQ threads.");
+    init_logging();
+    let supervisor = match SupervisorConfig::from_env() {
+        Ok(supervisor) => supervisor,
+        Err(err) => {
+            error!(error = %err, "Invalid health check settings");
+            std::process::exit(1);
+        }
+    };
+    let live = LiveStreams::default();
+    let (finished, finished_rx) = mpsc::unbounded_channel();
+    let (signal_managers, factory) = match initialise(live.clone(), finished) {
+        Ok(initialised) => initialised,
+        Err(err) => {
+            error!(error = %err, "Failed to initialise DAQ threads");
+            std::process::exit(1);
+        }
+    };
 
     let start = warp::path("start")
         .and(warp::path::param())
+        .and(warp::body::bytes())
         .and(with_signal_manager(Arc::clone(&signal_managers)))
         .and_then(start_stream);
 
@@ -28,20 +175,118 @@ async fn main() {
         .and(with_signal_manager(Arc::clone(&signal_managers)))
         .and_then(stop_stream);
 
+    let schedules = scheduler::store();
+
+    let reload = warp::path!("reload")
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and(with_factory(factory.clone()))
+        .and(with_schedules(Arc::clone(&schedules)))
+        .and_then(reload_streams);
+
     let buzzer = warp::path("buzzer")
         .and(warp::path::param())
         .and(with_signal_manager(Arc::clone(&signal_managers)))
         .and_then(trigger_buzzer);
 
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_method(warp::http::Method::POST);
-    let routes = warp::post().and(start.or(stop).or(buzzer)).with(cors);
+    let list = warp::path!("streams")
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and_then(list_streams);
+
+    let status = warp::path!("streams" / String)
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and_then(get_stream);
+
+    let spool_status = warp::path!("spool")
+        .and(with_publisher(factory.publisher.clone()))
+        .and_then(get_spool);
+
+    let metrics = warp::path!("metrics")
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and(with_publisher(factory.publisher.clone()))
+        .and_then(get_metrics);
+
+    let put = warp::path!("streams" / String)
+        .and(warp::body::json())
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and(with_factory(factory.clone()))
+        .and(with_schedules(Arc::clone(&schedules)))
+        .and_then(put_stream);
+
+    let delete = warp::path!("streams" / String)
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and(with_factory(factory.clone()))
+        .and(with_schedules(Arc::clone(&schedules)))
+        .and_then(delete_stream);
+
+    let live_stream = warp::path!("streams" / String / "live")
+        .and(warp::query::<LiveQuery>())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and(warp::any().map(move || live.clone()))
+        .and_then(watch_stream);
+
+    let add_schedule = warp::path!("streams" / String / "schedule")
+        .and(warp::body::json())
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and(with_schedules(Arc::clone(&schedules)))
+        .and_then(add_schedule);
+
+    let list_schedules = warp::path!("streams" / String / "schedule")
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and(with_schedules(Arc::clone(&schedules)))
+        .and_then(list_schedules);
+
+    let cancel_schedule = warp::path!("streams" / String / "schedule" / u64)
+        .and(with_schedules(Arc::clone(&schedules)))
+        .and_then(cancel_schedule);
+
+    let inventory = warp::path!("iom" / String / "inventory")
+        .and(with_signal_manager(Arc::clone(&signal_managers)))
+        .and(with_factory(factory.clone()))
+        .and_then(get_inventory);
+
+    let cors = warp::cors().allow_any_origin().allow_methods(vec![
+        warp::http::Method::GET,
+        warp::http::Method::POST,
+        warp::http::Method::PUT,
+        warp::http::Method::DELETE,
+    ]);
+    let routes = warp::post()
+        .and(start.or(stop).or(buzzer).or(reload).or(add_schedule))
+        .or(warp::get().and(
+            list.or(status)
+                .or(live_stream)
+                .or(spool_status)
+                .or(metrics)
+                .or(inventory)
+                .or(list_schedules),
+        ))
+        .or(warp::put().and(put))
+        .or(warp::delete().and(delete.or(cancel_schedule)))
+        .recover(handle_rejection)
+        .with(cors);
+
+    tokio::spawn(supervisor::supervise(
+        Arc::clone(&signal_managers),
+        factory.clone(),
+        supervisor,
+        finished_rx,
+    ));
+    tokio::spawn(scheduler::run(
+        Arc::clone(&signal_managers),
+        Arc::clone(&schedules),
+    ));
+    tokio::spawn(reload_on_hangup(
+        Arc::clone(&signal_managers),
+        factory.clone(),
+        schedules,
+    ));
 
     let (_, server) =
         warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], 3030), async move {
             match signal::ctrl_c().await {
-                Err(_) => eprintln!("Failed waiting for ^C"),
+                Err(_) => error!("Failed waiting for ^C"),
                 Ok(_) => signal_managers.lock().await.clear(),
             };
         });
@@ -55,16 +300,282 @@ fn with_signal_manager(
     warp::any().map(move || Arc::clone(&store))
 }
 
+fn with_schedules(
+    schedules: ScheduleStore,
+) -> impl Filter<Extract = (ScheduleStore,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&schedules))
+}
+
+fn with_publisher(
+    publisher: Publisher,
+) -> impl Filter<Extract = (Publisher,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || publisher.clone())
+}
+
+fn with_factory(
+    factory: StreamFactory,
+) -> impl Filter<Extract = (StreamFactory,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || factory.clone())
+}
+
+async fn list_streams(store: SignalStore) -> Result<impl warp::Reply, warp::Rejection> {
+    let store = store.lock().await;
+    let mut streams: Vec<StreamResponse> = store
+        .iter()
+        .map(|(name, manager)| StreamResponse::new(name, manager))
+        .collect();
+    streams.sort_by(|a, b| a.name.cmp(b.name));
+    Ok(warp::reply::json(&streams))
+}
+
+async fn get_stream(name: String, store: SignalStore) -> Result<impl warp::Reply, warp::Rejection> {
+    match store.lock().await.get(&name) {
+        Some(manager) => Ok(warp::reply::json(&StreamResponse::new(&name, manager))),
+        None => Err(warp::reject::custom(ApiError::UnknownStream(name))),
+    }
+}
+
+/// Checks that a stream can be saved with the given config: it mustn't be running, and the streams
+/// must still be valid with it. Returns the configs of all of the streams, including it.
+fn check_put(
+    store: &HashMap<String, SignalManager>,
+    name: &str,
+    config: &StreamConfig,
+) -> Result<HashMap<String, StreamConfig>, warp::Rejection> {
+    if let Some(manager) = store.get(name) {
+        if manager.status().running {
+            return Err(warp::reject::custom(ApiError::Daq(
+                DaqError::StreamStateError,
+            )));
+        }
+    }
+    let mut configs = bootstrap::configs(store);
+    configs.insert(name.to_string(), config.clone());
+    validate(&configs)
+        .map_err(|problems| warp::reject::custom(ApiError::InvalidConfig(problems)))?;
+    Ok(configs)
+}
+
+/// The new stream's IOM is opened without holding the streams, since that can take a while. The
+/// stream is only replaced once its config has been saved.
+async fn put_stream(
+    name: String,
+    config: StreamConfig,
+    store: SignalStore,
+    factory: StreamFactory,
+    schedules: ScheduleStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    check_put(&*store.lock().await, &name, &config)?;
+
+    let opener = factory.clone();
+    let (open_name, open_config) = (name.clone(), config.clone());
+    let backend = task::spawn_blocking(move || {
+        info_span!("stream", stream = %open_name, iom = %open_config.ip)
+            .in_scope(|| opener.open(&open_name, &open_config))
+    })
+    .await
+    .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()))
+    .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+
+    let mut store = store.lock().await;
+    // the streams may have changed while the IOM was being opened
+    let configs = check_put(&store, &name, &config)?;
+    factory
+        .persist(&configs)
+        .map_err(|err| warp::reject::custom(ApiError::PersistError(err)))?;
+    let status = match store.contains_key(&name) {
+        true => StatusCode::OK,
+        false => StatusCode::CREATED,
+    };
+    let manager = factory.manage(name.clone(), config, backend);
+    store.insert(name.clone(), manager);
+    info!(stream = %name, "Stream saved");
+    scheduler::cancel_stream(&schedules, &name).await;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&StreamResponse::new(&name, &store[&name])),
+        status,
+    ))
+}
+
+async fn delete_stream(
+    name: String,
+    store: SignalStore,
+    factory: StreamFactory,
+    schedules: ScheduleStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut store = store.lock().await;
+    match store.get(&name) {
+        Some(manager) if manager.status().running => Err(warp::reject::custom(ApiError::Daq(
+            DaqError::StreamStateError,
+        ))),
+        Some(_) => {
+            let mut configs = bootstrap::configs(&store);
+            configs.remove(&name);
+            factory
+                .persist(&configs)
+                .map_err(|err| warp::reject::custom(ApiError::PersistError(err)))?;
+            store.remove(&name);
+            info!(stream = %name, "Stream deleted");
+            scheduler::cancel_stream(&schedules, &name).await;
+            Ok(warp::reply())
+        }
+        None => Err(warp::reject::custom(ApiError::UnknownStream(name))),
+    }
+}
+
+async fn add_schedule(
+    name: String,
+    request: ScheduleRequest,
+    store: SignalStore,
+    schedules: ScheduleStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !store.lock().await.contains_key(&name) {
+        return Err(warp::reject::custom(ApiError::UnknownStream(name)));
+    }
+    let schedule = schedules
+        .lock()
+        .await
+        .add(&name, request)
+        .map_err(|message| {
+            warp::reject::custom(ApiError::InvalidRequest(format!(
+                "Invalid schedule: {}",
+                message
+            )))
+        })?;
+    info!(
+        stream = %name,
+        schedule = schedule.id,
+        start_at = %schedule.start_at,
+        "Acquisition scheduled"
+    );
+    Ok(warp::reply::with_status(
+        warp::reply::json(&schedule),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn list_schedules(
+    name: String,
+    store: SignalStore,
+    schedules: ScheduleStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !store.lock().await.contains_key(&name) {
+        return Err(warp::reject::custom(ApiError::UnknownStream(name)));
+    }
+    Ok(warp::reply::json(&schedules.lock().await.list(&name)))
+}
+
+async fn cancel_schedule(
+    name: String,
+    id: u64,
+    schedules: ScheduleStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match schedules.lock().await.cancel(&name, id) {
+        Some(_) => {
+            info!(stream = %name, schedule = id, "Schedule cancelled");
+            Ok(warp::reply())
+        }
+        None => Err(warp::reject::custom(ApiError::UnknownSchedule {
+            stream: name,
+            id,
+        })),
+    }
+}
+
+async fn reload_streams(
+    store: SignalStore,
+    factory: StreamFactory,
+    schedules: ScheduleStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match factory.reload(&store, &schedules).await {
+        Ok(report) => Ok(warp::reply::json(&report)),
+        Err(ConfigError::ValidationError { problems }) => {
+            Err(warp::reject::custom(ApiError::InvalidConfig(problems)))
+        }
+        Err(err) => Err(warp::reject::custom(ApiError::ConfigFile(err))),
+    }
+}
+
+async fn reload_on_hangup(store: SignalStore, factory: StreamFactory, schedules: ScheduleStore) {
+    let mut hangup = match signal::unix::signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!(error = %err, "Failed to listen for SIGHUP");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading stream config");
+        if let Err(err) = factory.reload(&store, &schedules).await {
+            error!(error = %err, "Failed to reload stream config");
+        }
+    }
+}
+
+async fn get_inventory(
+    ip: String,
+    store: SignalStore,
+    factory: StreamFactory,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let read = factory
+        .inventory(&*store.lock().await, &ip)
+        .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+    let result = match task::spawn_blocking(read).await {
+        Ok(result) => result,
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    };
+    match result {
+        Ok(inventory) => Ok(warp::reply::json(&inventory)),
+        Err(err) => Err(warp::reject::custom(ApiError::from(err))),
+    }
+}
+
+async fn get_spool(publisher: Publisher) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&publisher.spool.stats))
+}
+
+async fn get_metrics(
+    store: SignalStore,
+    publisher: Publisher,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let body = metrics::render(&*store.lock().await, &publisher.spool, &publisher.metrics);
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+/// The body is optional, and gives the session's label and metadata and how long it should run.
 async fn start_stream(
     topic: String,
+    body: Bytes,
     store: SignalStore,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let options: StartOptions = match body.is_empty() {
+        true => StartOptions::default(),
+        false => serde_json::from_slice(&body).map_err(|err| {
+            warp::reject::custom(ApiError::InvalidRequest(format!(
+                "Invalid start request: {}",
+                err
+            )))
+        })?,
+    };
+    if let Err(message) = options.validate() {
+        return Err(warp::reject::custom(ApiError::InvalidRequest(format!(
+            "Invalid start request: {}",
+            message
+        ))));
+    }
     match store.lock().await.get_mut(&topic) {
-        Some(manager) => match manager.start() {
-            Ok(_) => Ok(warp::reply()),
-            Err(_) => Err(warp::reject::not_found()), // TODO
+        Some(manager) => match manager.start(options).await {
+            Ok(_) => Ok(warp::reply::json(
+                &manager.status().session.map(SessionResponse::from),
+            )),
+            Err(err) => Err(warp::reject::custom(ApiError::from(err))),
         },
-        None => Err(warp::reject::not_found()),
+        None => Err(warp::reject::custom(ApiError::UnknownStream(topic))),
     }
 }
 
@@ -73,11 +584,11 @@ async fn stop_stream(
     store: SignalStore,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     match store.lock().await.get_mut(&topic) {
-        Some(manager) => match manager.stop() {
+        Some(manager) => match manager.stop().await {
             Ok(_) => Ok(warp::reply()),
-            Err(_) => Err(warp::reject::not_found()), // TODO
+            Err(err) => Err(warp::reject::custom(ApiError::from(err))),
         },
-        None => Err(warp::reject::not_found()),
+        None => Err(warp::reject::custom(ApiError::UnknownStream(topic))),
     }
 }
 
@@ -88,8 +599,177 @@ async fn trigger_buzzer(
     match store.lock().await.get_mut(&topic) {
         Some(manager) => match manager.trigger().await {
             Ok(_) => Ok(warp::reply()),
-            Err(_) => Err(warp::reject::not_found()),
+            Err(err) => Err(warp::reject::custom(ApiError::from(err))),
         },
-        None => Err(warp::reject::not_found()),
+        None => Err(warp::reject::custom(ApiError::UnknownStream(topic))),
+    }
+}
+
+async fn watch_stream(
+    name: String,
+    query: LiveQuery,
+    protocols: Option<String>,
+    ws: warp::ws::Ws,
+    store: SignalStore,
+    live: LiveStreams,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // subscribed under the lock so that the channels can't change in between
+    let (metadata, rx) = match (store.lock().await.get(&name), live.subscribe(&name)) {
+        (Some(manager), Some(rx)) => (manager.config().channel_metadata(), rx),
+        _ => return Err(warp::reject::custom(ApiError::UnknownStream(name))),
+    };
+    let (format, protocol) =
+        live::negotiate(&query, protocols.as_deref()).map_err(warp::reject::custom)?;
+    let subscription =
+        Subscription::new(name, format, &query, &metadata).map_err(warp::reject::custom)?;
+    let reply = ws.on_upgrade(move |socket| live::serve(socket, subscription, rx));
+    Ok(match protocol {
+        Some(protocol) => {
+            warp::reply::with_header(reply, "sec-websocket-protocol", protocol).into_response()
+        }
+        None => reply.into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootstrap::tests::manager;
+    use powerdna::{EventData, SensorData};
+    use tokio::sync::mpsc::Receiver;
+
+    type Queues = Vec<(Receiver<SensorData>, Receiver<EventData>)>;
+
+    /// Simulated streams with the given names, and their queues for the test to hold on to.
+    fn store(names: &[&str]) -> (SignalStore, Queues) {
+        let mut streams = HashMap::new();
+        let mut queues = Vec::new();
+        for name in names {
+            let (manager, sensor_rx, event_rx) = manager(name, 1000);
+            streams.insert(name.to_string(), manager);
+            queues.push((sensor_rx, event_rx));
+        }
+        (Arc::new(Mutex::new(streams)), queues)
+    }
+
+    /// Log output, shared with the test.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn json(reply: impl Reply) -> (StatusCode, serde_json::Value) {
+        let response = reply.into_response();
+        let status = response.status();
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn lists_streams_by_name() {
+        let (store, _queues) = store(&["b", "a"]);
+        let (status, body) = json(list_streams(store).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        let names: Vec<&str> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|stream| stream["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn describes_an_idle_stream() {
+        let (store, _queues) = store(&["rig"]);
+        let (status, body) = json(get_stream(String::from("rig"), store).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "rig");
+        assert_eq!(body["config"]["freq"], 1000);
+        assert_eq!(body["state"], "stopped");
+        assert_eq!(body["running"], false);
+        assert_eq!(body["fault"], serde_json::Value::Null);
+        assert_eq!(body["session"], serde_json::Value::Null);
+        assert_eq!(body["started_at"], serde_json::Value::Null);
+        assert_eq!(body["frames"], 0);
+    }
+
+    #[tokio::test]
+    async fn describes_a_running_stream() {
+        let (store, _queues) = store(&["rig"]);
+        let options = StartOptions {
+            label: Some(String::from("run 1")),
+            ..StartOptions::default()
+        };
+        let mut streams = store.lock().await;
+        let manager = streams.get_mut("rig").unwrap();
+        manager.start(options).await.unwrap();
+
+        let body = serde_json::to_value(StreamResponse::new("rig", manager)).unwrap();
+        assert_eq!(body["state"], "running");
+        assert_eq!(body["running"], true);
+        assert_eq!(body["session"]["label"], "run 1");
+        assert_eq!(
+            body["session"]["id"].as_str(),
+            manager.session().map(|session| session.id.as_str())
+        );
+        assert!(body["session"].get("duration_secs").is_none());
+        assert!(body["started_at"].is_string());
+
+        manager.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn logs_json_in_the_stream_span() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = json_logs(EnvFilter::new("info"), move || writer.clone()).finish();
+        let guard = tracing::subscriber::set_default(subscriber);
+        let (mut manager, _sensor_rx, _event_rx) = manager("rig", 1000);
+        manager.start(StartOptions::default()).await.unwrap();
+        let session = manager.session().unwrap().id.clone();
+        manager.stop().await.unwrap();
+        drop(guard);
+
+        let output = logs.0.lock().unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8_lossy(&output)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let started = lines
+            .iter()
+            .find(|line| line["fields"]["message"] == "Stream started")
+            .expect("no log of the stream starting");
+        assert_eq!(started["level"], "INFO");
+        assert_eq!(started["fields"]["session"], session.as_str());
+        assert_eq!(started["span"]["name"], "stream");
+        assert_eq!(started["span"]["stream"], "rig");
+        assert_eq!(started["span"]["iom"], "simulated");
+        assert!(lines
+            .iter()
+            .any(|line| line["fields"]["message"] == "Stream stopped"
+                && line["span"]["stream"] == "rig"));
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unknown_stream() {
+        let (store, _queues) = store(&["rig"]);
+        let rejection = match get_stream(String::from("other"), store).await {
+            Ok(_) => panic!("found a stream that doesn't exist"),
+            Err(rejection) => rejection,
+        };
+        let (status, body) = json(handle_rejection(rejection).await.unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "UnknownStream");
     }
 }
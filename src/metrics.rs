@@ -0,0 +1,431 @@
+use crate::spool::Spool;
+use powerdna::stats::{BoardStats, StreamStats};
+use powerdna::SignalManager;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the Kafka delivery latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+/// Kafka delivery outcomes for a single topic.
+#[derive(Default)]
+struct Delivery {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    /// Dropped because the broker rejected them for a reason that retrying wouldn't fix.
+    rejected: AtomicU64,
+    /// Cumulative, one per entry of `LATENCY_BUCKETS`.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_us: AtomicU64,
+}
+
+/// Kafka delivery outcomes by topic, including messages replayed from the spool.
+#[derive(Default)]
+pub struct KafkaMetrics {
+    topics: Mutex<BTreeMap<String, Arc<Delivery>>>,
+}
+
+impl KafkaMetrics {
+    fn topic(&self, topic: &str) -> Arc<Delivery> {
+        let mut topics = self.topics.lock().unwrap_or_else(|err| err.into_inner());
+        match topics.get(topic) {
+            Some(delivery) => Arc::clone(delivery),
+            None => Arc::clone(topics.entry(topic.to_string()).or_default()),
+        }
+    }
+
+    pub fn reject(&self, topic: &str) {
+        self.topic(topic).rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a delivery attempt and how long the broker took to acknowledge or reject it.
+    pub fn record(&self, topic: &str, latency: Duration, delivered: bool) {
+        let delivery = self.topic(topic);
+        if !delivered {
+            delivery.failed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        delivery.delivered.fetch_add(1, Ordering::Relaxed);
+        delivery
+            .latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        let seconds = latency.as_secs_f64();
+        for (bucket, bound) in delivery.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Name, help text and accessor of a counter.
+type Counter<T> = (&'static str, &'static str, fn(&T) -> &AtomicU64);
+
+/// A metric family in the Prometheus text exposition format.
+struct Family<'a> {
+    out: &'a mut String,
+    name: &'a str,
+}
+
+impl<'a> Family<'a> {
+    fn new(out: &'a mut String, name: &'a str, kind: &str, help: &str) -> Self {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        Family { out, name }
+    }
+
+    fn sample(&mut self, suffix: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        let _ = match labels.is_empty() {
+            true => writeln!(self.out, "{}{} {}", self.name, suffix, value),
+            false => writeln!(
+                self.out,
+                "{}{}{{{}}} {}",
+                self.name,
+                suffix,
+                labels.join(","),
+                value
+            ),
+        };
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders everything for `GET /metrics`.
+pub fn render(
+    streams: &HashMap<String, SignalManager>,
+    spool: &Spool,
+    kafka: &KafkaMetrics,
+) -> String {
+    let mut streams: Vec<(&String, &SignalManager)> = streams.iter().collect();
+    streams.sort_by(|a, b| a.0.cmp(b.0));
+    let mut out = String::new();
+
+    let mut family = Family::new(
+        &mut out,
+        "daiquiri_stream_running",
+        "gauge",
+        "Whether the stream is sampling.",
+    );
+    for (name, manager) in &streams {
+        family.sample("", &[("stream", name)], manager.status().running as u8);
+    }
+
+    let mut family = Family::new(
+        &mut out,
+        "daiquiri_stream_faulted",
+        "gauge",
+        "Whether the stream has faulted and is waiting to recover.",
+    );
+    for (name, manager) in &streams {
+        family.sample(
+            "",
+            &[("stream", name)],
+            manager.status().fault.is_some() as u8,
+        );
+    }
+
+    let mut family = Family::new(
+        &mut out,
+        "daiquiri_stream_queue_depth",
+        "gauge",
+        "Frames waiting in the stream's queues.",
+    );
+    for (name, manager) in &streams {
+        let status = manager.status();
+        family.sample(
+            "",
+            &[("stream", name), ("queue", "muxer")],
+            status.muxer_queue,
+        );
+        family.sample(
+            "",
+            &[("stream", name), ("queue", "publisher")],
+            status.publisher_queue,
+        );
+    }
+
+    let counters: [Counter<StreamStats>; 5] = [
+        (
+            "daiquiri_stream_frames_total",
+            "Frames produced by the stream's muxer.",
+            |stats| &stats.frames,
+        ),
+        (
+            "daiquiri_stream_frames_dropped_total",
+            "Frames dropped because a queue was full.",
+            |stats| &stats.frames_dropped,
+        ),
+        (
+            "daiquiri_stream_events_dropped_total",
+            "Events dropped because a queue was full.",
+            |stats| &stats.events_dropped,
+        ),
+        (
+            "daiquiri_stream_buzzer_events_total",
+            "Buzzer events reported by the output board.",
+            |stats| &stats.buzzer_events,
+        ),
+        (
+            "daiquiri_stream_data_gaps_total",
+            "Gaps found between the timestamps of consecutive scans.",
+            |stats| &stats.data_gaps,
+        ),
+    ];
+    for (metric, help, value) in counters {
+        let mut family = Family::new(&mut out, metric, "counter", help);
+        for (name, manager) in &streams {
+            family.sample(
+                "",
+                &[("stream", name)],
+                value(manager.stats()).load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    let board_counters: [Counter<BoardStats>; 5] = [
+        (
+            "daiquiri_board_frames_total",
+            "Frames produced by the input board.",
+            |board| &board.frames,
+        ),
+        (
+            "daiquiri_board_packets_lost_total",
+            "DQ_ePacketLost events seen by the input board.",
+            |board| &board.packets_lost,
+        ),
+        (
+            "daiquiri_board_buffer_errors_total",
+            "DQ_eBufferError events seen by the input board.",
+            |board| &board.buffer_errors,
+        ),
+        (
+            "daiquiri_board_packets_oob_total",
+            "DQ_ePacketOOB events seen by the input board.",
+            |board| &board.packets_oob,
+        ),
+        (
+            "daiquiri_board_timestamp_errors_total",
+            "Frames skipped because their timestamps couldn't be decoded.",
+            |board| &board.timestamp_errors,
+        ),
+    ];
+    for (metric, help, value) in board_counters {
+        let mut family = Family::new(&mut out, metric, "counter", help);
+        for (name, manager) in &streams {
+            for (device, board) in &manager.stats().boards {
+                family.sample(
+                    "",
+                    &[("stream", name), ("device", &device.to_string())],
+                    value(board).load(Ordering::Relaxed),
+                );
+            }
+        }
+    }
+
+    render_kafka(&mut out, kafka);
+
+    let stats = &spool.stats;
+    Family::new(
+        &mut out,
+        "daiquiri_spool_messages",
+        "gauge",
+        "Messages waiting in the Kafka spool.",
+    )
+    .sample("", &[], stats.messages.load(Ordering::Relaxed));
+    Family::new(
+        &mut out,
+        "daiquiri_spool_bytes",
+        "gauge",
+        "Bytes held by the Kafka spool.",
+    )
+    .sample("", &[], stats.bytes.load(Ordering::Relaxed));
+    Family::new(
+        &mut out,
+        "daiquiri_spool_dropped_total",
+        "counter",
+        "Messages lost because the spool was full.",
+    )
+    .sample("", &[], stats.dropped.load(Ordering::Relaxed));
+    Family::new(
+        &mut out,
+        "daiquiri_spool_replayed_total",
+        "counter",
+        "Messages delivered from the spool after an outage.",
+    )
+    .sample("", &[], stats.replayed.load(Ordering::Relaxed));
+
+    out
+}
+
+/// Kafka delivery counters and the latency histogram, by topic.
+fn render_kafka(out: &mut String, kafka: &KafkaMetrics) {
+    let topics: Vec<(String, Arc<Delivery>)> = kafka
+        .topics
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .map(|(topic, delivery)| (topic.clone(), Arc::clone(delivery)))
+        .collect();
+
+    let mut family = Family::new(
+        out,
+        "daiquiri_kafka_messages_delivered_total",
+        "counter",
+        "Messages acknowledged by the broker.",
+    );
+    for (topic, delivery) in &topics {
+        family.sample(
+            "",
+            &[("topic", topic)],
+            delivery.delivered.load(Ordering::Relaxed),
+        );
+    }
+
+    let mut family = Family::new(
+        out,
+        "daiquiri_kafka_delivery_failures_total",
+        "counter",
+        "Delivery attempts that failed, including retries from the spool.",
+    );
+    for (topic, delivery) in &topics {
+        family.sample(
+            "",
+            &[("topic", topic)],
+            delivery.failed.load(Ordering::Relaxed),
+        );
+    }
+
+    let mut family = Family::new(
+        out,
+        "daiquiri_kafka_messages_rejected_total",
+        "counter",
+        "Messages dropped because the broker rejected them for good.",
+    );
+    for (topic, delivery) in &topics {
+        family.sample(
+            "",
+            &[("topic", topic)],
+            delivery.rejected.load(Ordering::Relaxed),
+        );
+    }
+
+    let mut family = Family::new(
+        out,
+        "daiquiri_kafka_delivery_latency_seconds",
+        "histogram",
+        "Time taken for the broker to acknowledge a message.",
+    );
+    for (topic, delivery) in &topics {
+        for (bucket, bound) in delivery.buckets.iter().zip(LATENCY_BUCKETS) {
+            family.sample(
+                "_bucket",
+                &[("topic", topic), ("le", &bound.to_string())],
+                bucket.load(Ordering::Relaxed),
+            );
+        }
+        let count = delivery.delivered.load(Ordering::Relaxed);
+        family.sample("_bucket", &[("topic", topic), ("le", "+Inf")], count);
+        family.sample(
+            "_sum",
+            &[("topic", topic)],
+            delivery.latency_us.load(Ordering::Relaxed) as f64 / 1e6,
+        );
+        family.sample("_count", &[("topic", topic)], count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(out: &str, prefix: &str) -> Vec<String> {
+        out.lines()
+            .filter(|line| line.starts_with(prefix))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape("line\nbreak"), "line\\nbreak");
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn renders_labels_and_unlabelled_samples() {
+        let mut out = String::new();
+        let mut family = Family::new(&mut out, "m", "gauge", "Help.");
+        family.sample("", &[], 1);
+        family.sample("", &[("stream", "a\"b"), ("queue", "muxer")], 2);
+        assert_eq!(
+            out,
+            "# HELP m Help.\n# TYPE m gauge\nm 1\nm{stream=\"a\\\"b\",queue=\"muxer\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn renders_a_cumulative_latency_histogram() {
+        let kafka = KafkaMetrics::default();
+        kafka.record("t", Duration::from_millis(2), true);
+        kafka.record("t", Duration::from_millis(200), true);
+        kafka.record("t", Duration::from_secs(10), true);
+        kafka.record("t", Duration::from_millis(1), false);
+        kafka.reject("t");
+        let mut out = String::new();
+        render_kafka(&mut out, &kafka);
+
+        let histogram = "daiquiri_kafka_delivery_latency_seconds";
+        let buckets = lines(&out, &format!("{}_bucket", histogram));
+        assert_eq!(buckets.len(), LATENCY_BUCKETS.len() + 1);
+        assert_eq!(
+            buckets[0],
+            format!("{}_bucket{{topic=\"t\",le=\"0.001\"}} 0", histogram)
+        );
+        assert_eq!(
+            buckets[1],
+            format!("{}_bucket{{topic=\"t\",le=\"0.0025\"}} 1", histogram)
+        );
+        assert_eq!(
+            buckets[7],
+            format!("{}_bucket{{topic=\"t\",le=\"0.25\"}} 2", histogram)
+        );
+        assert_eq!(
+            buckets[9],
+            format!("{}_bucket{{topic=\"t\",le=\"5\"}} 2", histogram)
+        );
+        assert_eq!(
+            buckets[10],
+            format!("{}_bucket{{topic=\"t\",le=\"+Inf\"}} 3", histogram)
+        );
+        assert_eq!(
+            lines(&out, &format!("{}_sum", histogram)),
+            [format!("{}_sum{{topic=\"t\"}} 10.202", histogram)]
+        );
+        assert_eq!(
+            lines(&out, &format!("{}_count", histogram)),
+            [format!("{}_count{{topic=\"t\"}} 3", histogram)]
+        );
+        assert_eq!(
+            lines(&out, "daiquiri_kafka_delivery_failures_total{"),
+            ["daiquiri_kafka_delivery_failures_total{topic=\"t\"} 1"]
+        );
+        assert_eq!(
+            lines(&out, "daiquiri_kafka_messages_rejected_total{"),
+            ["daiquiri_kafka_messages_rejected_total{topic=\"t\"} 1"]
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use crate::SignalStore;
+use chrono::{DateTime, Utc};
+use powerdna::session::StartOptions;
+use powerdna::SignalManager;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time;
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// How long the scheduler sleeps when there is nothing to do, unless a schedule is added.
+const IDLE_WAIT: Duration = Duration::from_secs(3600);
+
+pub type ScheduleStore = Arc<Mutex<Schedules>>;
+
+/// When a schedule starts or stops its stream: either a UTC time, or a number of seconds from when
+/// the schedule is made (for a start) or from the start (for a stop).
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum When {
+    At(DateTime<Utc>),
+    In(f64),
+}
+
+/// Body of `POST /streams/:name/schedule`. The remaining fields are the usual start options.
+#[derive(Deserialize, Debug)]
+pub struct ScheduleRequest {
+    start: When,
+    #[serde(default)]
+    stop: Option<When>,
+    #[serde(flatten)]
+    options: StartOptions,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleState {
+    /// Waiting for its start time.
+    Pending,
+    /// Started, and waiting for its stop time.
+    Running,
+    /// The stream couldn't be started. Kept until it is cancelled so that it can be seen.
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Schedule {
+    pub id: u64,
+    pub stream: String,
+    pub start_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_at: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub options: StartOptions,
+    pub state: ScheduleState,
+    /// Id of the session it started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Scheduled starts and stops of streams, kept in memory. Schedules are removed once they have
+/// run their course.
+#[derive(Default)]
+pub struct Schedules {
+    next_id: u64,
+    schedules: BTreeMap<u64, Schedule>,
+    /// Wakes the scheduler when a schedule is added or cancelled.
+    changed: Arc<Notify>,
+}
+
+pub fn store() -> ScheduleStore {
+    Arc::new(Mutex::new(Schedules::default()))
+}
+
+impl Schedules {
+    /// A relative stop becomes the session's `duration_secs`, so that exactly that much data is
+    /// acquired, while an absolute one stops the stream at that time.
+    pub fn add(&mut self, stream: &str, request: ScheduleRequest) -> Result<Schedule, String> {
+        let now = Utc::now();
+        let start_at = match request.start {
+            When::At(at) if at < now => return Err(String::from("start is in the past")),
+            When::At(at) => at,
+            When::In(secs) => now + seconds(secs).ok_or("start must not be negative")?,
+        };
+        let timed = request.options.duration_secs.is_some() || request.options.samples.is_some();
+        let (stop_at, options) = match request.stop {
+            Some(_) if timed => {
+                return Err(String::from(
+                    "stop can't be given with duration_secs or samples",
+                ))
+            }
+            Some(When::At(at)) if at <= start_at => {
+                return Err(String::from("stop must be after start"))
+            }
+            Some(When::At(at)) => (Some(at), request.options),
+            Some(When::In(secs)) => (
+                None,
+                StartOptions {
+                    duration_secs: Some(secs),
+                    ..request.options
+                },
+            ),
+            None => (None, request.options),
+        };
+        options.validate()?;
+
+        self.next_id += 1;
+        let schedule = Schedule {
+            id: self.next_id,
+            stream: stream.to_string(),
+            start_at,
+            stop_at,
+            options,
+            state: ScheduleState::Pending,
+            session: None,
+            error: None,
+        };
+        self.schedules.insert(schedule.id, schedule.clone());
+        self.changed.notify_one();
+        Ok(schedule)
+    }
+
+    pub fn list(&self, stream: &str) -> Vec<Schedule> {
+        self.schedules
+            .values()
+            .filter(|schedule| schedule.stream == stream)
+            .cloned()
+            .collect()
+    }
+
+    /// Cancelling a running schedule leaves the stream running, without its scheduled stop.
+    pub fn cancel(&mut self, stream: &str, id: u64) -> Option<Schedule> {
+        match self.schedules.get(&id) {
+            Some(schedule) if schedule.stream == stream => {
+                self.changed.notify_one();
+                self.schedules.remove(&id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Cancels all of a stream's schedules, e.g. because it has been replaced or deleted. Returns
+    /// how many there were.
+    pub fn cancel_stream(&mut self, stream: &str) -> usize {
+        let before = self.schedules.len();
+        self.schedules
+            .retain(|_, schedule| schedule.stream != stream);
+        let cancelled = before - self.schedules.len();
+        if cancelled > 0 {
+            self.changed.notify_one();
+        }
+        cancelled
+    }
+
+    /// When the next schedule needs to start or stop its stream.
+    fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.schedules
+            .values()
+            .filter_map(|schedule| match schedule.state {
+                ScheduleState::Pending => Some(schedule.start_at),
+                ScheduleState::Running => schedule.stop_at,
+                ScheduleState::Failed => None,
+            })
+            .min()
+    }
+
+    /// Starts and stops the streams whose schedules are due.
+    async fn run_due(&mut self, streams: &mut HashMap<String, SignalManager>) {
+        let now = Utc::now();
+        let mut done = Vec::new();
+        for schedule in self.schedules.values_mut() {
+            let span = info_span!("stream", stream = %schedule.stream);
+            async {
+                match schedule.state {
+                    ScheduleState::Pending if schedule.start_at <= now => {
+                        let result = match streams.get_mut(&schedule.stream) {
+                            Some(manager) => match manager.start(schedule.options.clone()).await {
+                                Ok(_) => Ok(manager.session().map(|session| session.id.clone())),
+                                Err(err) => Err(err.to_string()),
+                            },
+                            None => Err(String::from("the stream no longer exists")),
+                        };
+                        match result {
+                            Ok(session) => {
+                                info!(schedule = schedule.id, "Started scheduled acquisition");
+                                schedule.session = session;
+                                match schedule.stop_at {
+                                    Some(_) => schedule.state = ScheduleState::Running,
+                                    None => done.push(schedule.id),
+                                }
+                            }
+                            Err(err) => {
+                                error!(schedule = schedule.id, error = %err, "Scheduled start failed");
+                                schedule.state = ScheduleState::Failed;
+                                schedule.error = Some(err);
+                            }
+                        }
+                    }
+                    ScheduleState::Running if matches!(schedule.stop_at, Some(at) if at <= now) => {
+                        // the stream is stopped even if it has been reconnected since, which starts
+                        // a new session
+                        if let Some(manager) = streams.get_mut(&schedule.stream) {
+                            if manager.status().running {
+                                match manager.stop().await {
+                                    Ok(_) => {
+                                        info!(schedule = schedule.id, "Stopped scheduled acquisition")
+                                    }
+                                    Err(err) => {
+                                        warn!(schedule = schedule.id, error = %err, "Scheduled stop failed")
+                                    }
+                                }
+                            }
+                        }
+                        done.push(schedule.id);
+                    }
+                    _ => (),
+                }
+            }
+            .instrument(span)
+            .await;
+        }
+        for id in done {
+            self.schedules.remove(&id);
+        }
+    }
+}
+
+/// Drops the schedules of a stream that has been replaced or deleted, so that they don't start
+/// a stream they weren't made for.
+pub async fn cancel_stream(schedules: &ScheduleStore, stream: &str) {
+    let cancelled = schedules.lock().await.cancel_stream(stream);
+    if cancelled > 0 {
+        info!(stream = %stream, cancelled, "Schedules cancelled");
+    }
+}
+
+fn seconds(secs: f64) -> Option<chrono::Duration> {
+    match secs.is_finite() && secs >= 0.0 {
+        true => chrono::Duration::from_std(Duration::from_secs_f64(secs)).ok(),
+        false => None,
+    }
+}
+
+/// Sleeps until the next schedule is due, or one is added or cancelled, and then drives the
+/// streams of any that are due.
+pub async fn run(store: SignalStore, schedules: ScheduleStore) {
+    let changed = Arc::clone(&schedules.lock().await.changed);
+    loop {
+        let wait = match schedules.lock().await.next_due() {
+            Some(at) => (at - Utc::now()).to_std().unwrap_or_default(),
+            None => IDLE_WAIT,
+        };
+        tokio::select! {
+            _ = time::sleep(wait) => (),
+            _ = changed.notified() => continue,
+        }
+        let mut streams = store.lock().await;
+        schedules.lock().await.run_due(&mut streams).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: serde_json::Value) -> ScheduleRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn rejected(body: serde_json::Value) -> String {
+        Schedules::default().add("rig", request(body)).unwrap_err()
+    }
+
+    #[test]
+    fn schedules_relative_and_absolute_times() {
+        let mut schedules = Schedules::default();
+        let start = Utc::now() + chrono::Duration::hours(1);
+        let stop = start + chrono::Duration::minutes(5);
+        let schedule = schedules
+            .add(
+                "rig",
+                request(serde_json::json!({ "start": start, "stop": stop })),
+            )
+            .unwrap();
+        assert_eq!(schedule.start_at, start);
+        assert_eq!(schedule.stop_at, Some(stop));
+        assert_eq!(schedule.state, ScheduleState::Pending);
+
+        let schedule = schedules
+            .add(
+                "rig",
+                request(serde_json::json!({ "start": 60, "stop": 30 })),
+            )
+            .unwrap();
+        assert_eq!(schedule.id, 2);
+        assert_eq!(schedule.stop_at, None);
+        assert_eq!(schedule.options.duration_secs, Some(30.0));
+        assert!(schedule.start_at > Utc::now());
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        let past = Utc::now() - chrono::Duration::minutes(1);
+        assert_eq!(
+            rejected(serde_json::json!({ "start": past })),
+            "start is in the past"
+        );
+        assert_eq!(
+            rejected(serde_json::json!({ "start": -1 })),
+            "start must not be negative"
+        );
+        let start = Utc::now() + chrono::Duration::hours(1);
+        assert_eq!(
+            rejected(serde_json::json!({ "start": start, "stop": start })),
+            "stop must be after start"
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_or_invalid_limits() {
+        assert_eq!(
+            rejected(serde_json::json!({ "start": 0, "stop": 10, "samples": 100 })),
+            "stop can't be given with duration_secs or samples"
+        );
+        assert!(Schedules::default()
+            .add("rig", request(serde_json::json!({ "start": 0, "stop": 0 })))
+            .is_err());
+        assert!(Schedules::default()
+            .add(
+                "rig",
+                request(serde_json::json!({ "start": 0, "samples": 0 }))
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn cancels_a_streams_schedules() {
+        let mut schedules = Schedules::default();
+        for stream in ["rig", "rig", "other"] {
+            schedules
+                .add(stream, request(serde_json::json!({ "start": 60 })))
+                .unwrap();
+        }
+        assert_eq!(schedules.cancel_stream("rig"), 2);
+        assert!(schedules.list("rig").is_empty());
+        assert_eq!(schedules.list("other").len(), 1);
+        assert_eq!(schedules.cancel_stream("rig"), 0);
+    }
+}
@@ -0,0 +1,800 @@
+use crate::bootstrap::ConfigError;
+use crate::metrics::KafkaMetrics;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+const DEFAULT_DIRECTORY: &str = "/var/lib/daiquiri/spool";
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+/// Segments are deleted once fully replayed, so this bounds how much disk is held by records that
+/// have already been delivered.
+const SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+const SEGMENT_EXTENSION: &str = "spool";
+/// Segment and offset of the oldest record that hasn't been delivered yet.
+const HEAD_FILE: &str = "head";
+/// Messages removed between writes of the head file. After a crash, up to this many messages may
+/// be delivered again.
+const HEAD_SYNC_INTERVAL: u64 = 64;
+/// Starts every record.
+const RECORD_MARKER: u32 = u32::MAX;
+/// Length written in place of a message key that isn't set.
+const NO_KEY: u32 = u32::MAX;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// What to do with a message that doesn't fit in a full spool.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpoolPolicy {
+    /// Hold up the publisher until the broker is back and the spool has drained.
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+/// Read from `SPOOL_DIR`, `SPOOL_MAX_BYTES` and `SPOOL_POLICY`.
+pub struct SpoolConfig {
+    directory: PathBuf,
+    max_bytes: u64,
+    policy: SpoolPolicy,
+}
+
+impl SpoolConfig {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let invalid = |variable: &str, message: &str| ConfigError::InvalidSpoolSetting {
+            variable: variable.to_string(),
+            message: message.to_string(),
+        };
+        let max_bytes = match env::var("SPOOL_MAX_BYTES") {
+            Ok(value) => match value.trim().parse::<u64>() {
+                Ok(max_bytes) if max_bytes > 0 => max_bytes,
+                _ => {
+                    return Err(invalid(
+                        "SPOOL_MAX_BYTES",
+                        "expected a positive number of bytes",
+                    ))
+                }
+            },
+            Err(_) => DEFAULT_MAX_BYTES,
+        };
+        let policy = match env::var("SPOOL_POLICY").as_deref() {
+            Err(_) | Ok("block") => SpoolPolicy::Block,
+            Ok("drop-oldest") => SpoolPolicy::DropOldest,
+            Ok("drop-newest") => SpoolPolicy::DropNewest,
+            Ok(_) => {
+                return Err(invalid(
+                    "SPOOL_POLICY",
+                    "expected block, drop-oldest or drop-newest",
+                ))
+            }
+        };
+        Ok(SpoolConfig {
+            directory: PathBuf::from(
+                env::var("SPOOL_DIR").unwrap_or_else(|_| String::from(DEFAULT_DIRECTORY)),
+            ),
+            max_bytes,
+            policy,
+        })
+    }
+}
+
+/// A Kafka message waiting to be delivered.
+pub struct SpooledMessage {
+    pub topic: String,
+    /// Unkeyed messages are spread across partitions by the producer.
+    pub key: Option<String>,
+    /// Left to the partitioner if not set.
+    pub partition: Option<i32>,
+    pub headers: Vec<(String, String)>,
+    pub payload: Vec<u8>,
+}
+
+fn put_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(field);
+}
+
+/// Reads a record's fields, keeping count of the bytes consumed.
+struct RecordReader<'a, R> {
+    reader: &'a mut R,
+    read: u64,
+}
+
+impl<R: Read> RecordReader<'_, R> {
+    fn word(&mut self) -> Result<u32, io::Error> {
+        let mut word = [0u8; 4];
+        self.reader.read_exact(&mut word)?;
+        self.read += 4;
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn bytes(&mut self, len: u32) -> Result<Vec<u8>, io::Error> {
+        let mut bytes = vec![0u8; len as usize];
+        self.reader.read_exact(&mut bytes)?;
+        self.read += len as u64;
+        Ok(bytes)
+    }
+
+    fn field(&mut self) -> Result<Vec<u8>, io::Error> {
+        let len = self.word()?;
+        self.bytes(len)
+    }
+
+    fn string(&mut self, len: u32) -> Result<String, io::Error> {
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn text(&mut self) -> Result<String, io::Error> {
+        let len = self.word()?;
+        self.string(len)
+    }
+}
+
+impl SpooledMessage {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len() as usize);
+        bytes.extend_from_slice(&RECORD_MARKER.to_le_bytes());
+        put_field(&mut bytes, self.topic.as_bytes());
+        match &self.key {
+            Some(key) => put_field(&mut bytes, key.as_bytes()),
+            None => bytes.extend_from_slice(&NO_KEY.to_le_bytes()),
+        }
+        bytes.extend_from_slice(&self.partition.unwrap_or(-1).to_le_bytes());
+        bytes.extend_from_slice(&(self.headers.len() as u32).to_le_bytes());
+        for (name, value) in &self.headers {
+            put_field(&mut bytes, name.as_bytes());
+            put_field(&mut bytes, value.as_bytes());
+        }
+        put_field(&mut bytes, &self.payload);
+        bytes
+    }
+
+    /// Reads a record, returning it along with its size on disk.
+    fn decode(reader: &mut impl Read) -> Result<(Self, u64), io::Error> {
+        let mut record = RecordReader { reader, read: 0 };
+        if record.word()? != RECORD_MARKER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record doesn't start with the record marker",
+            ));
+        }
+        let topic = record.text()?;
+        let key = match record.word()? {
+            NO_KEY => None,
+            len => Some(record.string(len)?),
+        };
+        let partition = Some(record.word()? as i32).filter(|partition| *partition >= 0);
+        let headers = (0..record.word()?)
+            .map(|_| Ok((record.text()?, record.text()?)))
+            .collect::<Result<Vec<(String, String)>, io::Error>>()?;
+        let message = SpooledMessage {
+            topic,
+            key,
+            partition,
+            headers,
+            payload: record.field()?,
+        };
+        Ok((message, record.read))
+    }
+
+    /// Size on disk once encoded.
+    fn len(&self) -> u64 {
+        let key = self.key.as_ref().map_or(0, String::len);
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| 8 + name.len() + value.len())
+            .sum();
+        (24 + self.topic.len() + key + headers + self.payload.len()) as u64
+    }
+}
+
+/// Spool depth, as reported by `GET /spool`.
+#[derive(Default, Serialize)]
+pub struct SpoolStats {
+    /// Messages waiting to be delivered.
+    pub messages: AtomicU64,
+    pub bytes: AtomicU64,
+    /// Messages lost because the spool was full.
+    pub dropped: AtomicU64,
+    /// Messages delivered from the spool after an outage.
+    pub replayed: AtomicU64,
+}
+
+struct SpoolState {
+    /// Ids of the segment files, oldest first. The last one is appended to.
+    segments: VecDeque<u64>,
+    /// Offset of the oldest undelivered message in the first segment.
+    head: u64,
+    /// Size of the last segment.
+    tail: u64,
+    bytes: u64,
+    messages: u64,
+    /// Messages removed since the head file was last written.
+    unsynced: u64,
+}
+
+/// Where a message was read from, so that it is only removed if it is still the oldest. Segment
+/// ids aren't reused, so a position can't match a message spooled later.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    segment: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// Bounded on-disk queue of Kafka messages that couldn't be delivered, replayed in order once the
+/// broker is reachable again.
+pub struct Spool {
+    directory: PathBuf,
+    max_bytes: u64,
+    policy: SpoolPolicy,
+    state: Mutex<SpoolState>,
+    /// Signalled when messages are removed from the spool.
+    space: Notify,
+    /// Signalled when messages are added to the spool.
+    pending: Notify,
+    pub stats: SpoolStats,
+}
+
+impl Spool {
+    /// Opens the spool, picking up anything left undelivered by a previous run.
+    pub fn open(config: SpoolConfig) -> Result<Self, io::Error> {
+        fs::create_dir_all(&config.directory)?;
+
+        let mut segments: Vec<u64> = fs::read_dir(&config.directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                match path.extension().and_then(|extension| extension.to_str()) {
+                    Some(SEGMENT_EXTENSION) => path.file_stem()?.to_str()?.parse().ok(),
+                    _ => None,
+                }
+            })
+            .collect();
+        segments.sort_unstable();
+        let mut segments: VecDeque<u64> = segments.into();
+
+        let head = match fs::read_to_string(config.directory.join(HEAD_FILE)) {
+            Ok(text) => match text.trim().split_once(':') {
+                Some((segment, offset)) if segments.front() == segment.parse().ok().as_ref() => {
+                    offset.parse().unwrap_or(0)
+                }
+                _ => 0,
+            },
+            Err(_) => 0,
+        };
+        if segments.is_empty() {
+            segments.push_back(0);
+            File::create(config.directory.join(format!("0.{}", SEGMENT_EXTENSION)))?;
+        }
+        let first = config
+            .directory
+            .join(format!("{}.{}", segments[0], SEGMENT_EXTENSION));
+        let head = head.min(fs::metadata(first)?.len());
+
+        let spool = Spool {
+            directory: config.directory,
+            max_bytes: config.max_bytes,
+            policy: config.policy,
+            state: Mutex::new(SpoolState {
+                segments,
+                head,
+                tail: 0,
+                bytes: 0,
+                messages: 0,
+                unsynced: 0,
+            }),
+            space: Notify::new(),
+            pending: Notify::new(),
+            stats: SpoolStats::default(),
+        };
+
+        let mut state = spool.lock();
+        let segments: Vec<u64> = state.segments.iter().copied().collect();
+        for (i, &segment) in segments.iter().enumerate() {
+            let start = match i {
+                0 => state.head,
+                _ => 0,
+            };
+            let (messages, end) = spool.scan(segment, start)?;
+            state.messages += messages;
+            state.bytes += end - start;
+            state.tail = end;
+        }
+        if state.messages > 0 {
+            info!(
+                messages = state.messages,
+                "Found undelivered messages in the Kafka spool"
+            );
+            spool.pending.notify_one();
+        }
+        spool.update_stats(&state);
+        drop(state);
+        Ok(spool)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SpoolState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.directory
+            .join(format!("{}.{}", segment, SEGMENT_EXTENSION))
+    }
+
+    /// Counts the complete messages in a segment, returning the count and the offset after the
+    /// last one. A message cut short by a crash is truncated away.
+    fn scan(&self, segment: u64, start: u64) -> Result<(u64, u64), io::Error> {
+        let path = self.segment_path(segment);
+        let mut reader = BufReader::new(File::open(&path)?);
+        reader.seek(SeekFrom::Start(start))?;
+        let (mut messages, mut end) = (0, start);
+        loop {
+            match SpooledMessage::decode(&mut reader) {
+                Ok((_, len)) => {
+                    messages += 1;
+                    end += len;
+                }
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+        }
+        OpenOptions::new().write(true).open(&path)?.set_len(end)?;
+        Ok((messages, end))
+    }
+
+    fn update_stats(&self, state: &SpoolState) {
+        self.stats.messages.store(state.messages, Ordering::Relaxed);
+        self.stats.bytes.store(state.bytes, Ordering::Relaxed);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().messages == 0
+    }
+
+    /// Runs some of the spool's file I/O on the blocking thread pool, off the async runtime.
+    async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        io: impl FnOnce(&Spool) -> Result<T, io::Error> + Send + 'static,
+    ) -> Result<T, io::Error> {
+        let spool = Arc::clone(self);
+        task::spawn_blocking(move || io(&spool))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Appends a message, applying the overflow policy if the spool is full.
+    pub async fn push(self: &Arc<Self>, message: SpooledMessage) -> Result<(), io::Error> {
+        let mut message = message;
+        loop {
+            // registered before checking for space so that a message delivered in between
+            // isn't missed
+            let space = self.space.notified();
+            message = match self.blocking(move |spool| spool.try_push(message)).await? {
+                Some(message) => message,
+                None => return Ok(()),
+            };
+            space.await;
+        }
+    }
+
+    /// Appends a message if it fits, or can be made to fit, handing it back if it has to wait for
+    /// space.
+    fn try_push(&self, message: SpooledMessage) -> Result<Option<SpooledMessage>, io::Error> {
+        let mut state = self.lock();
+        let fits = |state: &SpoolState| {
+            state.messages == 0 || state.bytes + message.len() <= self.max_bytes
+        };
+        if !fits(&state) {
+            match self.policy {
+                SpoolPolicy::Block => return Ok(Some(message)),
+                SpoolPolicy::DropNewest => {
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(None);
+                }
+                SpoolPolicy::DropOldest => {
+                    while !fits(&state) {
+                        self.remove_head(&mut state)?;
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        self.append(&mut state, &message)?;
+        self.update_stats(&state);
+        self.pending.notify_one();
+        Ok(None)
+    }
+
+    fn append(&self, state: &mut SpoolState, message: &SpooledMessage) -> Result<(), io::Error> {
+        if state.tail >= SEGMENT_BYTES {
+            let next = state.segments.back().map_or(0, |last| last + 1);
+            File::create(self.segment_path(next))?;
+            state.segments.push_back(next);
+            state.tail = 0;
+        }
+        let segment = *state.segments.back().unwrap_or(&0);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(self.segment_path(segment))?;
+        file.write_all(&message.encode())?;
+        state.tail += message.len();
+        state.bytes += message.len();
+        state.messages += 1;
+        Ok(())
+    }
+
+    /// Oldest undelivered message, and where it was read from.
+    async fn peek(self: &Arc<Self>) -> Result<Option<(SpooledMessage, Position)>, io::Error> {
+        self.blocking(|spool| {
+            let state = spool.lock();
+            match state.segments.front() {
+                Some(&segment) if state.messages > 0 => {
+                    let (message, len) = spool.read(segment, state.head)?;
+                    let position = Position {
+                        segment,
+                        offset: state.head,
+                        len,
+                    };
+                    Ok(Some((message, position)))
+                }
+                _ => Ok(None),
+            }
+        })
+        .await
+    }
+
+    /// Removes a message once it has been delivered, unless it has been dropped to make space in
+    /// the meantime.
+    async fn pop(self: &Arc<Self>, position: Position) -> Result<(), io::Error> {
+        self.blocking(move |spool| {
+            let mut state = spool.lock();
+            if state.messages == 0
+                || state.segments.front() != Some(&position.segment)
+                || state.head != position.offset
+            {
+                return Ok(());
+            }
+            spool.advance(&mut state, position.len)?;
+            spool.update_stats(&state);
+            spool.space.notify_waiters();
+            Ok(())
+        })
+        .await
+    }
+
+    fn read(&self, segment: u64, offset: u64) -> Result<(SpooledMessage, u64), io::Error> {
+        let mut reader = BufReader::new(File::open(self.segment_path(segment))?);
+        reader.seek(SeekFrom::Start(offset))?;
+        SpooledMessage::decode(&mut reader)
+    }
+
+    fn remove_head(&self, state: &mut SpoolState) -> Result<(), io::Error> {
+        let segment = match state.segments.front() {
+            Some(segment) if state.messages > 0 => *segment,
+            _ => return Ok(()),
+        };
+        let (_, len) = self.read(segment, state.head)?;
+        self.advance(state, len)
+    }
+
+    /// Moves the head past the oldest message, which is `len` bytes long.
+    fn advance(&self, state: &mut SpoolState, len: u64) -> Result<(), io::Error> {
+        let segment = match state.segments.front() {
+            Some(segment) => *segment,
+            None => return Ok(()),
+        };
+        state.head += len;
+        state.bytes -= len;
+        state.messages -= 1;
+        state.unsynced += 1;
+
+        let end = match state.segments.len() {
+            1 => state.tail,
+            _ => fs::metadata(self.segment_path(segment))?.len(),
+        };
+        if state.head >= end {
+            // nothing left to deliver -- move on to a new segment rather than letting this one
+            // grow, or truncating it and reusing its id
+            if state.segments.len() == 1 {
+                File::create(self.segment_path(segment + 1))?;
+                state.segments.push_back(segment + 1);
+                state.tail = 0;
+            }
+            fs::remove_file(self.segment_path(segment))?;
+            state.segments.pop_front();
+            state.head = 0;
+            self.sync_head(state)?;
+        } else if state.unsynced >= HEAD_SYNC_INTERVAL {
+            self.sync_head(state)?;
+        }
+        Ok(())
+    }
+
+    fn sync_head(&self, state: &mut SpoolState) -> Result<(), io::Error> {
+        let head_segment = state.segments.front().copied().unwrap_or(0);
+        fs::write(
+            self.directory.join(HEAD_FILE),
+            format!("{}:{}", head_segment, state.head),
+        )?;
+        state.unsynced = 0;
+        Ok(())
+    }
+
+    /// Waits until there is something to replay.
+    async fn wait_for_messages(&self) {
+        while self.is_empty() {
+            self.pending.notified().await;
+        }
+    }
+}
+
+async fn send(
+    producer: &FutureProducer,
+    metrics: &KafkaMetrics,
+    message: &SpooledMessage,
+) -> Result<(), KafkaError> {
+    let mut headers = OwnedHeaders::new_with_capacity(message.headers.len());
+    for (name, value) in &message.headers {
+        headers = headers.add(name, value.as_str());
+    }
+    let mut record = FutureRecord::<str, [u8]>::to(message.topic.as_str())
+        .payload(&message.payload)
+        .headers(headers);
+    if let Some(key) = &message.key {
+        record = record.key(key.as_str());
+    }
+    if let Some(partition) = message.partition {
+        record = record.partition(partition);
+    }
+    let sent = Instant::now();
+    let result = producer.send(record, Duration::from_secs(180)).await;
+    metrics.record(&message.topic, sent.elapsed(), result.is_ok());
+    result.map(|_| ()).map_err(|(err, _)| err)
+}
+
+/// Whether sending a message again later might succeed. Anything else, such as a message that is
+/// too large or a topic that doesn't exist, would only hold up the spool.
+fn retriable(err: &KafkaError) -> bool {
+    matches!(
+        err.rdkafka_error_code(),
+        Some(
+            RDKafkaErrorCode::QueueFull
+                | RDKafkaErrorCode::MessageTimedOut
+                | RDKafkaErrorCode::OperationTimedOut
+                | RDKafkaErrorCode::RequestTimedOut
+                | RDKafkaErrorCode::BrokerTransportFailure
+                | RDKafkaErrorCode::AllBrokersDown
+                | RDKafkaErrorCode::Resolve
+                | RDKafkaErrorCode::NetworkException
+                | RDKafkaErrorCode::BrokerNotAvailable
+                | RDKafkaErrorCode::LeaderNotAvailable
+                | RDKafkaErrorCode::NotLeaderForPartition
+                | RDKafkaErrorCode::NotEnoughReplicas
+                | RDKafkaErrorCode::NotEnoughReplicasAfterAppend
+        )
+    )
+}
+
+fn reject(metrics: &KafkaMetrics, message: &SpooledMessage, err: &KafkaError) {
+    metrics.reject(&message.topic);
+    error!(
+        topic = %message.topic,
+        key = ?message.key,
+        error = %err,
+        "Kafka rejected message, dropping it"
+    );
+}
+
+/// Sends a message to Kafka, spooling it if the broker can't be reached. Messages go straight to
+/// the spool while it is being replayed so that they are delivered in order. Messages the broker
+/// rejects outright are dropped.
+pub async fn deliver(
+    producer: &FutureProducer,
+    spool: &Arc<Spool>,
+    metrics: &KafkaMetrics,
+    message: SpooledMessage,
+) {
+    if spool.is_empty() {
+        match send(producer, metrics, &message).await {
+            Ok(_) => return,
+            Err(err) if retriable(&err) => {
+                warn!(topic = %message.topic, error = %err, "Failed to send to Kafka, spooling")
+            }
+            Err(err) => return reject(metrics, &message, &err),
+        }
+    }
+    if let Err(err) = spool.push(message).await {
+        error!(error = %err, "Failed to spool Kafka message");
+    }
+}
+
+/// Re-sends spooled messages oldest first, backing off while the broker is unreachable.
+pub async fn replay(producer: FutureProducer, spool: Arc<Spool>, metrics: Arc<KafkaMetrics>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        spool.wait_for_messages().await;
+        let (message, position) = match spool.peek().await {
+            Ok(Some(head)) => head,
+            Ok(None) => continue,
+            Err(err) => {
+                error!(error = %err, "Failed to read Kafka spool");
+                sleep(MAX_BACKOFF).await;
+                continue;
+            }
+        };
+        match send(&producer, &metrics, &message).await {
+            Ok(_) => {
+                backoff = MIN_BACKOFF;
+                spool.stats.replayed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) if retriable(&err) => {
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+            Err(err) => reject(&metrics, &message, &err),
+        }
+        if let Err(err) = spool.pop(position).await {
+            error!(error = %err, "Failed to update Kafka spool");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(name: &str, max_bytes: u64, policy: SpoolPolicy) -> Arc<Spool> {
+        let directory =
+            env::temp_dir().join(format!("daiquiri-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let config = SpoolConfig {
+            directory,
+            max_bytes,
+            policy,
+        };
+        Arc::new(Spool::open(config).unwrap())
+    }
+
+    fn reopen(spool: &Spool) -> Spool {
+        Spool::open(SpoolConfig {
+            directory: spool.directory.clone(),
+            max_bytes: spool.max_bytes,
+            policy: spool.policy,
+        })
+        .unwrap()
+    }
+
+    fn message(payload: &[u8]) -> SpooledMessage {
+        SpooledMessage {
+            topic: String::from("t"),
+            key: None,
+            partition: None,
+            headers: Vec::new(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    async fn payloads(spool: &Arc<Spool>) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        while let Some((message, position)) = spool.peek().await.unwrap() {
+            payloads.push(message.payload);
+            spool.pop(position).await.unwrap();
+        }
+        payloads
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let keyed = SpooledMessage {
+            topic: String::from("sensors"),
+            key: Some(String::from("V-sensor")),
+            partition: Some(3),
+            headers: vec![(String::from("site"), String::from("lab2"))],
+            payload: vec![1, 2, 3],
+        };
+        let bytes = keyed.encode();
+        assert_eq!(bytes.len() as u64, keyed.len());
+        let (decoded, len) = SpooledMessage::decode(&mut bytes.as_slice()).unwrap();
+        assert_eq!(len, keyed.len());
+        assert_eq!(decoded.topic, keyed.topic);
+        assert_eq!(decoded.key, keyed.key);
+        assert_eq!(decoded.partition, keyed.partition);
+        assert_eq!(decoded.headers, keyed.headers);
+        assert_eq!(decoded.payload, keyed.payload);
+
+        let (decoded, _) = SpooledMessage::decode(&mut message(b"x").encode().as_slice()).unwrap();
+        assert_eq!(decoded.key, None);
+        assert_eq!(decoded.partition, None);
+    }
+
+    #[tokio::test]
+    async fn replays_in_order_and_across_restarts() {
+        let spool = open("order", DEFAULT_MAX_BYTES, SpoolPolicy::Block);
+        let count = HEAD_SYNC_INTERVAL + 3;
+        for i in 0..count {
+            spool.push(message(&i.to_le_bytes())).await.unwrap();
+        }
+        for _ in 0..HEAD_SYNC_INTERVAL + 1 {
+            let (_, position) = spool.peek().await.unwrap().unwrap();
+            spool.pop(position).await.unwrap();
+        }
+
+        // the last message removed since the head file was written is delivered again
+        let spool = Arc::new(reopen(&spool));
+        assert_eq!(spool.stats.messages.load(Ordering::Relaxed), 3);
+        let expected: Vec<Vec<u8>> = (HEAD_SYNC_INTERVAL..count)
+            .map(|i| i.to_le_bytes().to_vec())
+            .collect();
+        assert_eq!(payloads(&spool).await, expected);
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&spool.directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_messages_when_full() {
+        let len = message(&[0]).len();
+        let spool = open("drop-oldest", 2 * len, SpoolPolicy::DropOldest);
+        for i in 0..4u8 {
+            spool.push(message(&[i])).await.unwrap();
+        }
+        assert_eq!(spool.stats.dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(payloads(&spool).await, [[2], [3]]);
+        fs::remove_dir_all(&spool.directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_the_newest_messages_when_full() {
+        let len = message(&[0]).len();
+        let spool = open("drop-newest", 2 * len, SpoolPolicy::DropNewest);
+        for i in 0..4u8 {
+            spool.push(message(&[i])).await.unwrap();
+        }
+        assert_eq!(spool.stats.dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(payloads(&spool).await, [[0], [1]]);
+        fs::remove_dir_all(&spool.directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_a_message_that_replaced_the_one_being_replayed() {
+        let len = message(&[0]).len();
+        let spool = open("race", len, SpoolPolicy::DropOldest);
+        spool.push(message(&[0])).await.unwrap();
+        let (_, position) = spool.peek().await.unwrap().unwrap();
+        // dropped to make space while it is being sent
+        spool.push(message(&[1])).await.unwrap();
+        spool.pop(position).await.unwrap();
+        assert_eq!(payloads(&spool).await, [[1]]);
+        fs::remove_dir_all(&spool.directory).unwrap();
+    }
+
+    #[test]
+    fn only_retries_errors_that_may_clear_up() {
+        let error = |code| KafkaError::MessageProduction(code);
+        assert!(retriable(&error(RDKafkaErrorCode::QueueFull)));
+        assert!(retriable(&error(RDKafkaErrorCode::MessageTimedOut)));
+        assert!(retriable(&error(RDKafkaErrorCode::AllBrokersDown)));
+        assert!(!retriable(&error(RDKafkaErrorCode::MessageSizeTooLarge)));
+        assert!(!retriable(&error(
+            RDKafkaErrorCode::TopicAuthorizationFailed
+        )));
+        assert!(!retriable(&KafkaError::Canceled));
+    }
+}
@@ -0,0 +1,399 @@
+use crate::bootstrap::{ConfigError, StreamFactory};
+use crate::SignalStore;
+use powerdna::config::StreamConfig;
+use powerdna::daq::DaqBackend;
+use powerdna::{DaqError, StreamState};
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::panic;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task;
+use tokio::time::{self, Instant};
+use tracing::{info, info_span, warn};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How streams are watched for faults and whether they are reconnected.
+pub struct SupervisorConfig {
+    interval: Duration,
+    reconnect: bool,
+    max_backoff: Duration,
+}
+
+impl SupervisorConfig {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|variable| env::var(variable).ok())
+    }
+
+    /// Reads the settings through `var`, which looks up an environment variable.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let invalid = |variable: &str| ConfigError::InvalidHealthSetting {
+            variable: variable.to_string(),
+            message: String::from("expected a positive number"),
+        };
+        let interval = match var("HEALTH_CHECK_INTERVAL_MS") {
+            Some(value) => match value.trim().parse::<u64>() {
+                Ok(ms) if ms > 0 => Duration::from_millis(ms),
+                _ => return Err(invalid("HEALTH_CHECK_INTERVAL_MS")),
+            },
+            None => DEFAULT_INTERVAL,
+        };
+        let max_backoff = match var("RECONNECT_MAX_BACKOFF_SECS") {
+            Some(value) => match value.trim().parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(invalid("RECONNECT_MAX_BACKOFF_SECS")),
+            },
+            None => DEFAULT_MAX_BACKOFF,
+        };
+        Ok(SupervisorConfig {
+            interval,
+            reconnect: matches!(var("RECONNECT").as_deref(), Some("true") | Some("1")),
+            max_backoff,
+        })
+    }
+}
+
+/// When a faulted stream is next tried, doubling the delay after each failed attempt.
+struct Backoff {
+    delay: Duration,
+    next: Instant,
+}
+
+impl Backoff {
+    /// Tries straight away, then after `delay`.
+    fn new(delay: Duration) -> Self {
+        Backoff {
+            delay,
+            next: Instant::now(),
+        }
+    }
+
+    fn due(&self) -> bool {
+        Instant::now() >= self.next
+    }
+
+    /// Puts the next attempt off by twice the last delay, up to `max`.
+    fn failed(&mut self, max: Duration) {
+        self.delay = cmp::min(self.delay * 2, max);
+        self.next = Instant::now() + self.delay;
+    }
+}
+
+/// Watches the streams' health and remembers when each faulted stream is next reconnected.
+struct Supervisor {
+    config: SupervisorConfig,
+    backoffs: HashMap<String, Backoff>,
+}
+
+impl Supervisor {
+    fn new(config: SupervisorConfig) -> Self {
+        Supervisor {
+            config,
+            backoffs: HashMap::new(),
+        }
+    }
+
+    /// Checks every stream's health, and returns the faulted streams that are due to be
+    /// reconnected. Idle streams' IOMs are pinged without holding the streams, since a ping blocks
+    /// until the IOM answers or times out.
+    async fn check(&mut self, store: &SignalStore) -> Vec<(String, StreamConfig)> {
+        let mut idle = Vec::new();
+        for (name, manager) in store.lock().await.iter_mut() {
+            manager.check_health().await;
+            if let Some(backend) = manager.idle_backend() {
+                idle.push((name.clone(), backend));
+            }
+        }
+
+        let unresponsive = task::spawn_blocking(move || {
+            idle.into_iter()
+                .filter_map(|(name, backend)| match backend.ping() {
+                    Ok(_) => None,
+                    Err(err) => Some((name, backend, err)),
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()));
+
+        let mut streams = store.lock().await;
+        for (name, backend, err) in unresponsive {
+            // the stream may have been replaced, removed or started in the meantime
+            if let Some(manager) = streams.get_mut(&name) {
+                manager.fault_unresponsive(&backend, err).await;
+            }
+        }
+
+        self.backoffs.retain(|name, _| {
+            streams
+                .get(name)
+                .is_some_and(|manager| manager.status().state == StreamState::Faulted)
+        });
+        if !self.config.reconnect {
+            return Vec::new();
+        }
+        let mut due = Vec::new();
+        for (name, manager) in streams.iter() {
+            if manager.status().state != StreamState::Faulted {
+                continue;
+            }
+            let interval = self.config.interval;
+            let backoff = self
+                .backoffs
+                .entry(name.clone())
+                .or_insert_with(|| Backoff::new(interval));
+            if backoff.due() {
+                due.push((name.clone(), manager.config().clone()));
+            }
+        }
+        due
+    }
+
+    /// Reconnects a faulted stream over an IOM connection made by `open`, which is called without
+    /// holding the streams. Backs off if either fails.
+    async fn reconnect<F>(
+        &mut self,
+        store: &SignalStore,
+        name: String,
+        stream_config: StreamConfig,
+        open: F,
+    ) where
+        F: FnOnce(&str, &StreamConfig) -> Result<DaqBackend, DaqError> + Send + 'static,
+    {
+        let span = info_span!("stream", stream = %name, iom = %stream_config.ip);
+        let open_span = span.clone();
+        let (name, stream_config, opened) = task::spawn_blocking(move || {
+            let opened = open_span.in_scope(|| open(&name, &stream_config));
+            (name, stream_config, opened)
+        })
+        .await
+        .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()));
+
+        let mut streams = store.lock().await;
+        let result = match streams.get_mut(&name) {
+            // the stream may have been replaced, removed or recovered in the meantime
+            Some(manager)
+                if *manager.config() == stream_config
+                    && manager.status().state == StreamState::Faulted =>
+            {
+                match opened {
+                    Ok(backend) => manager.reconnect(backend).await,
+                    Err(err) => Err(err),
+                }
+            }
+            _ => return,
+        };
+        let _span = span.entered();
+        match result {
+            Ok(_) => {
+                info!("Recovered faulted stream");
+                self.backoffs.remove(&name);
+            }
+            Err(err) => {
+                if let Some(backoff) = self.backoffs.get_mut(&name) {
+                    backoff.failed(self.config.max_backoff);
+                    warn!(error = %err, retry_in = ?backoff.delay, "Failed to reconnect");
+                }
+            }
+        }
+    }
+}
+
+/// Checks every stream's health once per interval and, if enabled, reconnects faulted streams.
+/// Streams are also checked as soon as one reports a completed timed acquisition, so that its
+/// hardware is released straight away. IOMs are pinged and opened without holding the streams,
+/// since that can take a while.
+pub async fn supervise(
+    store: SignalStore,
+    factory: StreamFactory,
+    config: SupervisorConfig,
+    mut finished: UnboundedReceiver<String>,
+) {
+    let mut ticker = time::interval(config.interval);
+    let mut supervisor = Supervisor::new(config);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => (),
+            Some(_) = finished.recv() => (),
+        }
+        for (name, stream_config) in supervisor.check(&store).await {
+            let factory = factory.clone();
+            supervisor
+                .reconnect(&store, name, stream_config, move |name, config| {
+                    factory.open(name, config)
+                })
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use powerdna::daq::simulated::SimulatedDaq;
+    use powerdna::{PowerDnaError, SignalManager};
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
+
+    fn supervisor(reconnect: bool) -> Supervisor {
+        Supervisor::new(SupervisorConfig {
+            interval: Duration::from_millis(1),
+            reconnect,
+            max_backoff: Duration::from_secs(1),
+        })
+    }
+
+    fn simulated(config: &StreamConfig) -> DaqBackend {
+        let simulation = config.simulation.clone().unwrap();
+        Arc::new(SimulatedDaq::new(simulation))
+    }
+
+    /// A store holding one idle stream, named `rig`, on the simulated backend. Its queues are
+    /// dropped, as the tests don't start it.
+    fn store() -> SignalStore {
+        let config: StreamConfig = serde_json::from_value(json!({
+            "ip": "192.168.100.2",
+            "freq": 1000,
+            "frame_size": 10,
+            "boards": [{ "device": 0, "channels": [{ "id": 0, "gain": 1 }] }],
+            "outputs": [],
+            "simulation": {},
+        }))
+        .unwrap();
+        let (sensor_tx, _) = mpsc::channel(1);
+        let (event_tx, _) = mpsc::channel(1);
+        let (finished, _) = mpsc::unbounded_channel();
+        let manager = SignalManager::new(
+            String::from("rig"),
+            config.clone(),
+            simulated(&config),
+            sensor_tx,
+            event_tx,
+            finished,
+            None,
+        );
+        Arc::new(Mutex::new(HashMap::from([(String::from("rig"), manager)])))
+    }
+
+    /// Faults the stream as if its IOM had stopped answering pings.
+    async fn fault(store: &SignalStore) {
+        let mut streams = store.lock().await;
+        let manager = streams.get_mut("rig").unwrap();
+        let backend = manager.idle_backend().unwrap();
+        assert!(
+            manager
+                .fault_unresponsive(&backend, PowerDnaError::TimeoutError)
+                .await
+        );
+    }
+
+    async fn state(store: &SignalStore) -> StreamState {
+        store.lock().await["rig"].status().state
+    }
+
+    #[test]
+    fn reads_the_config_from_the_environment() {
+        let config = SupervisorConfig::from_vars(|_| None).unwrap();
+        assert_eq!(config.interval, DEFAULT_INTERVAL);
+        assert!(!config.reconnect);
+        assert_eq!(config.max_backoff, DEFAULT_MAX_BACKOFF);
+
+        let vars = HashMap::from([
+            ("HEALTH_CHECK_INTERVAL_MS", "250"),
+            ("RECONNECT", "true"),
+            ("RECONNECT_MAX_BACKOFF_SECS", " 30 "),
+        ]);
+        let config =
+            SupervisorConfig::from_vars(|var| vars.get(var).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.interval, Duration::from_millis(250));
+        assert!(config.reconnect);
+        assert_eq!(config.max_backoff, Duration::from_secs(30));
+
+        for variable in ["HEALTH_CHECK_INTERVAL_MS", "RECONNECT_MAX_BACKOFF_SECS"] {
+            for value in ["0", "-1", "soon"] {
+                let result =
+                    SupervisorConfig::from_vars(|var| (var == variable).then(|| value.to_string()));
+                match result {
+                    Err(ConfigError::InvalidHealthSetting { variable: name, .. }) => {
+                        assert_eq!(name, variable)
+                    }
+                    _ => panic!("{}={} was accepted", variable, value),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_limit() {
+        let mut backoff = Backoff::new(Duration::from_secs(2));
+        assert!(backoff.due());
+        let delays: Vec<u64> = (0..4)
+            .map(|_| {
+                backoff.failed(Duration::from_secs(10));
+                backoff.delay.as_secs()
+            })
+            .collect();
+        assert_eq!(delays, [4, 8, 10, 10]);
+        assert!(!backoff.due());
+    }
+
+    #[tokio::test]
+    async fn leaves_responsive_streams_alone() {
+        let store = store();
+        assert!(supervisor(true).check(&store).await.is_empty());
+        assert_eq!(state(&store).await, StreamState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn ignores_a_ping_of_a_replaced_connection() {
+        let store = store();
+        let mut streams = store.lock().await;
+        let manager = streams.get_mut("rig").unwrap();
+        let stale = simulated(manager.config());
+        assert!(
+            !manager
+                .fault_unresponsive(&stale, PowerDnaError::TimeoutError)
+                .await
+        );
+        assert_eq!(manager.status().state, StreamState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn only_reconnects_when_enabled() {
+        let store = store();
+        fault(&store).await;
+        assert!(supervisor(false).check(&store).await.is_empty());
+        assert_eq!(state(&store).await, StreamState::Faulted);
+    }
+
+    #[tokio::test]
+    async fn reconnects_faulted_streams_with_backoff() {
+        let store = store();
+        fault(&store).await;
+        let mut supervisor = supervisor(true);
+
+        let mut due = supervisor.check(&store).await;
+        assert_eq!(due.len(), 1);
+        let (name, config) = due.remove(0);
+        supervisor
+            .reconnect(&store, name, config, |_, _| Err(DaqError::BufferError))
+            .await;
+        assert_eq!(state(&store).await, StreamState::Faulted);
+        assert_eq!(supervisor.backoffs["rig"].delay, Duration::from_millis(2));
+        assert!(supervisor.check(&store).await.is_empty());
+
+        time::sleep(Duration::from_millis(5)).await;
+        let mut due = supervisor.check(&store).await;
+        assert_eq!(due.len(), 1);
+        let (name, config) = due.remove(0);
+        supervisor
+            .reconnect(&store, name, config, |_, config| Ok(simulated(config)))
+            .await;
+        assert_eq!(state(&store).await, StreamState::Stopped);
+        assert!(supervisor.backoffs.is_empty());
+    }
+}